@@ -15,6 +15,11 @@ impl Wheel {
         Color::Green,
         Color::Cyan,
         Color::Yellow,
+        Color::BrightMagenta,
+        Color::BrightBlue,
+        Color::BrightGreen,
+        Color::BrightCyan,
+        Color::BrightYellow,
       ],
       style: Style::default().bold(),
     }
@@ -1,4 +1,6 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, Args};
+
+use crate::taskfile;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(
@@ -6,6 +8,7 @@ use clap::Parser;
   about = "Process Control is an operator for interdependent processes.
 https://crates.io/crates/psctl",
   long_about = None,
+  args_conflicts_with_subcommands = true,
   after_help = "EXAMPLES:
 
     $ psctl 'a: echo A' 'b: echo B=file:///tmp/file' 'c +a,b: echo C'"
@@ -17,11 +20,23 @@ pub struct Options {
   pub verbose: bool,
   #[clap(long, short='q', help="Enable quiet mode, only managed process output and errors are displayed")]
   pub quiet: bool,
-  #[clap(long, short='f', help="Load process specifiers from a taskfile")]
-  pub file: Option<String>,
+  #[clap(long, short='f', global=true, number_of_values=1, help="Load process specifiers from a taskfile. When more than one taskfile is provided, tasks defined in later files are merged into tasks with the same name in earlier files. When neither a taskfile nor specifiers are provided, $PSCTL_FILE is used, if set, otherwise psctl.yaml, psctl.yml or .psctl.yaml is looked for in the working directory and its parents")]
+  pub file: Vec<String>,
+  #[clap(long, global=true, value_enum, help="The format of the taskfiles provided, instead of the one determined from their name or extension; YAML is assumed when neither identifies one")]
+  pub format: Option<taskfile::Format>,
+  #[clap(long, global=true, help="Report unknown fields in taskfiles as warnings instead of errors, to read taskfiles written for a newer version of psctl")]
+  pub lenient: bool,
+  #[clap(long, short='p', global=true, number_of_values=1, help="Apply a profile defined by the taskfile; more than one profile may be applied, in order. When no profile is provided, those listed in $PSCTL_PROFILE, separated by commas, are applied")]
+  pub profile: Vec<String>,
+  #[clap(long, help="Act as an init process: reap orphaned processes while running and stop every remaining descendant on shutdown, even those that left their task's process group. This is enabled automatically when psctl runs as PID 1")]
+  pub init: bool,
+  #[clap(long, help="Run until this task exits and exit with its status; any other task that fails before it exits is an error. This takes precedence over a task marked 'main' and the taskfile's exit policy")]
+  pub until: Option<String>,
+  #[clap(subcommand)]
+  pub command: Option<Command>,
   #[clap(
     help_heading="SPECIFIERS",
-    help="Task specifiers to run and manage. When a taskfile is provided, it is preferred over specifiers provided on the command line.
+    help="Task specifiers to run and manage. When a taskfile is provided, these tasks are added to the tasks it defines, replacing any with the same name.
 
 Any number of task specifiers may be provided as arguments. Each specifier has the following form:
 
@@ -36,8 +51,18 @@ READINESS CHECKS
     Readiness checks are expressed as a URL. The following types are supported:
 
     http(s)://...      The check passes when the URL returns 2XX
-    file://...         The check passes when the file exists
+    file://...         The check passes when the file exists. In a version 2
+                       taskfile, a check can be an object with options:
+                         fresh      the file changed since the task started
+                         non_empty  the file isn't empty
+                         contains   the file's contents match a pattern
+                         socket     the file is a socket accepting connections
+                         remove     the file is removed before the task starts
     shell://<command>  The check passes when the command exits with status 0
+    listen:<port>      The check passes when the task, or a process descended
+                       from it, listens for TCP connections on the port, like
+                       'listen:8080', or on any port with 'listen:any'; this
+                       reads /proc, so it's only supported on Linux
 
 EXAMPLE
 
@@ -46,6 +71,64 @@ EXAMPLE
   pub specs: Vec<String>,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+  #[clap(about="Convert a taskfile to another format")]
+  Convert(ConvertOptions),
+  #[clap(about="Import a Procfile or Compose file as a taskfile")]
+  Import(ImportOptions),
+  #[clap(about="Upgrade a taskfile to the latest version")]
+  Migrate(MigrateOptions),
+  #[clap(about="Print the JSON Schema for taskfiles")]
+  Schema(SchemaOptions),
+  #[clap(about="Check a taskfile for errors, on its own and with each of its profiles applied; when profiles are provided, only they are checked, together")]
+  Check(CheckOptions),
+  #[clap(about="Make psctl, running the same taskfile, read it again and apply the changes; only tasks that were added, removed or changed, and the tasks that depend on them, are started, stopped or restarted")]
+  Reload(ReloadOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ConvertOptions {
+  #[clap(long, short='t', value_enum, help="The format to convert to; when omitted, it is determined from the output file's extension")]
+  pub to: Option<taskfile::Format>,
+  #[clap(long, short='o', help="Write the converted taskfile to this path instead of STDOUT")]
+  pub output: Option<String>,
+  #[clap(help="The taskfile to convert")]
+  pub input: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ImportOptions {
+  #[clap(long, value_enum, help="The format to import from; when omitted, it is determined from the input file's name")]
+  pub from: Option<taskfile::Format>,
+  #[clap(long, short='t', value_enum, help="The format to write; when omitted, it is determined from the output file's extension, otherwise YAML is written")]
+  pub to: Option<taskfile::Format>,
+  #[clap(long, short='o', help="Write the taskfile to this path instead of STDOUT")]
+  pub output: Option<String>,
+  #[clap(help="The Procfile or Compose file to import")]
+  pub input: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MigrateOptions {
+  #[clap(long, short='o', help="Write the upgraded taskfile to this path, or '-' for STDOUT, instead of rewriting the taskfile")]
+  pub output: Option<String>,
+  #[clap(help="The taskfile to upgrade")]
+  pub input: String,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SchemaOptions {
+  #[clap(long, short='o', help="Write the schema to this path instead of STDOUT")]
+  pub output: Option<String>,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CheckOptions {}
+
+#[derive(Args, Debug, Clone)]
+pub struct ReloadOptions {}
+
 impl Options {
   pub fn debug(&self) -> bool {
     self.debug
@@ -1,17 +1,30 @@
 use std::io;
 use std::fmt;
 
-use serde_yaml;
-
 use crate::waiter;
 use crate::runner;
+use crate::taskfile;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
   IOError(io::Error),
-  YamlError(serde_yaml::Error),
+  TaskfileError(taskfile::error::Error),
   WaiterError(waiter::error::Error),
   RunnerError(runner::error::Error),
+  UsageError(String),
+}
+
+impl Error {
+  /// The status psctl exits with when it fails with this error. A task that
+  /// exits before the main task finishes is distinguished from the main task
+  /// failing, which exits with its own status.
+  pub fn code(&self) -> i32 {
+    match self {
+      Self::RunnerError(runner::error::Error::EarlyExitError(..)) => runner::EARLY_EXIT_CODE,
+      _ => 1,
+    }
+  }
 }
 
 impl From<io::Error> for Error {
@@ -20,9 +33,9 @@ impl From<io::Error> for Error {
   }
 }
 
-impl From<serde_yaml::Error> for Error {
-  fn from(err: serde_yaml::Error) -> Self {
-    Self::YamlError(err)
+impl From<taskfile::error::Error> for Error {
+  fn from(err: taskfile::error::Error) -> Self {
+    Self::TaskfileError(err)
   }
 }
 
@@ -42,9 +55,10 @@ impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
       Self::IOError(err) => err.fmt(f),
-      Self::YamlError(err) => err.fmt(f),
+      Self::TaskfileError(err) => err.fmt(f),
       Self::WaiterError(err) => err.fmt(f),
       Self::RunnerError(err) => err.fmt(f),
+      Self::UsageError(msg) => write!(f, "{}", msg),
     }
   }
 }
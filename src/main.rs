@@ -1,17 +1,22 @@
 use std::fs;
+use std::env;
+use std::path;
 use std::process;
 
 use tokio::sync::mpsc;
-use futures::executor;
 
 use clap::Parser;
-use serde::{Serialize, Deserialize};
+use nix::unistd::Pid;
+use nix::sys::signal;
+use nix::sys::signal::Signal;
 use colored::Colorize;
 
 mod waiter;
 mod runner;
 mod error;
 mod config;
+mod schema;
+mod taskfile;
 mod colorwheel;
 
 #[tokio::main]
@@ -20,66 +25,277 @@ async fn main() {
     Ok(code)  => process::exit(code),
     Err(err)  => {
       eprintln!("{}", &format!("* * * {}", err).yellow().bold());
-      process::exit(1);
+      process::exit(err.code());
     },
   };
 }
 
 async fn cmd() -> Result<i32, error::Error> {
   let opts = config::Options::parse();
+  match &opts.command {
+    Some(config::Command::Convert(sub)) => return convert(&opts, sub),
+    Some(config::Command::Import(sub)) => return import(sub),
+    Some(config::Command::Migrate(sub)) => return migrate(&opts, sub),
+    Some(config::Command::Schema(sub)) => return schema(sub),
+    Some(config::Command::Check(_)) => return check(&opts),
+    Some(config::Command::Reload(_)) => return reload(&opts),
+    None => {},
+  }
+
   let (tx, mut rx) = mpsc::channel(1);
 
-  ctrlc::set_handler(move || {
-    executor::block_on(async {
-      tx.send(()).await
-    }).expect("Failed to propagate signal")
-  }).expect("Failed to set Ctrl-C handler");
-
-  let procs = if let Some(file) = &opts.file {
-    read_procs(file)?.tasks
-  }else{
-    let mut procs = Vec::new();
-    for e in &opts.specs {
-      procs.push(runner::Process::parse(e)?);
-    }
-    procs
-  };
+  runner::signals::shutdown(tx)?;
+  let mut forward = runner::signals::Forward::new()?; // before anything starts, so that no forwarded signal kills psctl
+
+  let files = taskfile::resolve(&opts, env::var_os("PSCTL_FILE"))?;
+  let conf = taskfile::Config::from_options(&opts);
+  let (procs, file) = read_procs(&opts, &conf, &files)?;
 
   if opts.debug() {
     let name = env!("CARGO_PKG_NAME");
     let version = env!("CARGO_PKG_VERSION");
     eprintln!("{}", &format!("====> {} {}, at your service", name, version).bold().cyan());
   }
+  if opts.verbose() {
+    for file in &files {
+      eprintln!("{}", &format!("====> using taskfile: {}", file.display()).bold());
+    }
+    for profile in &conf.profiles {
+      eprintln!("{}", &format!("====> using profile: {}", profile).bold());
+    }
+  }
 
   if procs.is_empty() {
-    Ok(0) // nothing to do
-  }else{
-    Ok(runner::Pod::new(opts, procs).exec(&mut rx).await?)
+    return Ok(0); // nothing to do
   }
+  let pod = runner::Pod::new(opts.clone(), procs)?.with_exit_policy(file.exit_policy()).with_hooks(file.setup(), file.teardown());
+  let pod = match &opts.until {
+    Some(main) => pod.with_main(main),
+    None => pod,
+  };
+  let pod = match opts.init || runner::init::is_pid1() {
+    true  => pod.with_init(runner::init::Init::new()?),
+    false => pod,
+  };
+  let pod = match files.is_empty() {
+    true  => pod,
+    false => {
+      let files = files.clone();
+      pod.with_reload(Box::new(move || {
+        match read_procs(&opts, &taskfile::Config::from_options(&opts), &files) {
+          Ok((procs, _)) => Ok(procs), // the exit policy and hooks are fixed once the pod is running
+          Err(err) => Err(err.to_string()),
+        }
+      }))
+    },
+  };
+  // The PID file is only written once SIGHUP is handled, so that 'psctl
+  // reload' can't reach a psctl that a hangup would stop.
+  let pidfile = match files.is_empty() {
+    true  => None,
+    false => Some(taskfile::pidfile(&files)?),
+  };
+  let pidfile = match pidfile {
+    Some(pidfile) => match write_pidfile(&pidfile)? {
+      Ok(()) => Some(pidfile),
+      Err(pid) => {
+        eprintln!("{}", &format!("* * * psctl is already running these taskfiles [{}]; 'psctl reload' reloads that one", pid).yellow());
+        None
+      },
+    },
+    None => None,
+  };
+  let res = pod.exec(&mut forward, &mut rx).await;
+  if let Some(pidfile) = &pidfile {
+    let _ = fs::remove_file(pidfile);
+  }
+  Ok(res?)
 }
 
-#[derive(Serialize, Deserialize)]
-struct SpecFile {
-  version: u32,
-  tasks: Vec<runner::Process>,
+// Read the tasks to run from taskfiles, with the configured profiles applied,
+// and the specifiers provided on the command line, which replace tasks with
+// the same name. The taskfiles are returned along with them, for the settings
+// they make besides tasks.
+fn read_procs(opts: &config::Options, conf: &taskfile::Config, files: &[path::PathBuf]) -> Result<(Vec<runner::Process>, taskfile::Taskfile), error::Error> {
+  let file = taskfile::read(conf, files)?;
+  omitted(file.omitted());
+  let mut procs = file.procs(&conf.profiles)?;
+  for e in &opts.specs {
+    let proc = runner::Process::parse(e)?;
+    match procs.iter_mut().find(|e| e.key() == proc.key()) {
+      Some(base) => *base = proc,
+      None => procs.push(proc),
+    }
+  }
+  Ok((procs, file))
+}
+
+// Report what imported files define which was left out of their tasks.
+fn omitted(notes: &[String]) {
+  for e in notes {
+    eprintln!("{}", &format!("* * * {}", e).yellow());
+  }
 }
 
-impl SpecFile {
-  fn with_tasks(&self, tasks: Vec<runner::Process>) -> Self {
-    Self {
-      version: self.version,
-      tasks: tasks,
+fn convert(opts: &config::Options, sub: &config::ConvertOptions) -> Result<i32, error::Error> {
+  let to = match (sub.to, &sub.output) {
+    (Some(to), _) => to,
+    (None, Some(output)) => match taskfile::Format::from_path(path::Path::new(output)) {
+      Some(to) => to,
+      None => return Err(error::Error::UsageError(format!("Cannot determine the format to convert to from: {}; use --to", output))),
+    },
+    (None, None) => return Err(error::Error::UsageError("Provide the format to convert to with --to".to_string())),
+  };
+  let res = taskfile::convert(&taskfile::Config::from_options(opts), path::Path::new(&sub.input), to)?;
+  omitted(&res.omitted.iter().map(|e| format!("{}: {}", sub.input, e)).collect::<Vec<String>>());
+  match &sub.output {
+    Some(output) => fs::write(output, res.data)?,
+    None => print!("{}", res.data),
+  }
+  Ok(0)
+}
+
+fn import(sub: &config::ImportOptions) -> Result<i32, error::Error> {
+  let input = path::Path::new(&sub.input);
+  let from = match sub.from.or_else(|| taskfile::Format::from_path(input)) {
+    Some(from) if from.is_import() => from,
+    _ => return Err(error::Error::UsageError(format!("Cannot determine the format to import from: {}; use --from procfile or --from compose", sub.input))),
+  };
+  let to = match (sub.to, &sub.output) {
+    (Some(to), _) => to,
+    (None, Some(output)) => taskfile::Format::from_path(path::Path::new(output)).unwrap_or(taskfile::Format::Yaml),
+    (None, None) => taskfile::Format::Yaml,
+  };
+  let conf = taskfile::Config{format: Some(from), lenient: false, profiles: vec![]};
+  let res = taskfile::convert(&conf, input, to)?;
+  omitted(&res.omitted.iter().map(|e| format!("{}: {}", sub.input, e)).collect::<Vec<String>>());
+  match &sub.output {
+    Some(output) => fs::write(output, res.data)?,
+    None => print!("{}", res.data),
+  }
+  Ok(0)
+}
+
+fn migrate(opts: &config::Options, sub: &config::MigrateOptions) -> Result<i32, error::Error> {
+  let res = taskfile::migrate(&taskfile::Config::from_options(opts), path::Path::new(&sub.input))?;
+  if res.version == taskfile::LATEST_VERSION && sub.output.is_none() {
+    eprintln!("{}", &format!("====> {}: already version {}", sub.input, res.version).bold());
+    return Ok(0);
+  }
+  if !res.verbatim {
+    eprintln!("{}", &format!("====> {}: comments and formatting could not be preserved", sub.input).yellow().bold());
+  }
+  match sub.output.as_deref() {
+    Some("-") => print!("{}", res.data),
+    Some(output) => fs::write(output, res.data)?,
+    None => fs::write(&sub.input, res.data)?,
+  }
+  if res.version != taskfile::LATEST_VERSION && !opts.quiet() {
+    eprintln!("{}", &format!("====> {}: upgraded from version {} to {}", sub.input, res.version, taskfile::LATEST_VERSION).bold());
+  }
+  Ok(0)
+}
+
+fn check(opts: &config::Options) -> Result<i32, error::Error> {
+  let files = taskfile::resolve(opts, env::var_os("PSCTL_FILE"))?;
+  if files.is_empty() {
+    return Err(error::Error::UsageError("No taskfile found; provide one with --file".to_string()));
+  }
+  let conf = taskfile::Config::from_options(opts);
+  let file = taskfile::read(&conf, &files)?;
+  omitted(file.omitted());
+  let sets: Vec<Vec<String>> = match conf.profiles.is_empty() {
+    true  => [vec![]].into_iter().chain(file.profiles().iter().map(|e| vec![e.to_string()])).collect(),
+    false => vec![conf.profiles.clone()],
+  };
+
+  let mut code = 0;
+  for profiles in &sets {
+    let desc = match profiles.is_empty() {
+      true  => "no profile".to_string(),
+      false => format!("profile {}", profiles.join(", ")),
+    };
+    let res: Result<(), error::Error> = match file.procs(profiles) {
+      Ok(procs) => runner::validate(&procs, &file.exit_policy()).map_err(|e| e.into()),
+      Err(err) => Err(err.into()),
+    };
+    match res {
+      Ok(_) => if !opts.quiet() {
+        eprintln!("{}", &format!("====> {}: ok", desc).bold());
+      },
+      Err(err) => {
+        eprintln!("{}", &format!("* * * {}: {}", desc, err).yellow().bold());
+        code = 1;
+      },
     }
   }
+  Ok(code)
 }
 
-fn read_procs(path: &str) -> Result<SpecFile, error::Error> {
-  let origin = runner::Origin::new(path);
-  let data = fs::read_to_string(path)?;
-  let spec: SpecFile = serde_yaml::from_str(&data)?;
-  let mut tasks: Vec<runner::Process> = Vec::new();
-  for e in &spec.tasks {
-    tasks.push(e.with_origin(origin.clone()));
+fn reload(opts: &config::Options) -> Result<i32, error::Error> {
+  let files = taskfile::resolve(opts, env::var_os("PSCTL_FILE"))?;
+  if files.is_empty() {
+    return Err(error::Error::UsageError("No taskfile found; provide one with --file".to_string()));
+  }
+  let pidfile = taskfile::pidfile(&files)?;
+  let pid: i32 = match read_pidfile(&pidfile) {
+    Some(pid) => pid,
+    None => return Err(error::Error::UsageError(format!("psctl is not running: {}", files.iter().map(|e| e.display().to_string()).collect::<Vec<String>>().join(", ")))),
+  };
+  if let Err(err) = signal::kill(Pid::from_raw(pid), Signal::SIGHUP) {
+    return Err(error::Error::UsageError(format!("Could not signal psctl [{}]: {}", pid, err)));
+  }
+  if !opts.quiet() {
+    eprintln!("{}", &format!("====> reloading: {}", pid).bold());
+  }
+  Ok(0)
+}
+
+// Record psctl's process ID in a file, which is created afresh so that it
+// can't be swapped for a link to some other file. A file that is left from
+// a psctl which has exited is replaced; when another psctl is running the
+// same taskfiles, its process ID is returned instead.
+fn write_pidfile(path: &path::Path) -> Result<Result<(), i32>, error::Error> {
+  use std::io::Write;
+  use std::os::unix::fs::OpenOptionsExt;
+  for _ in 0..2 {
+    let res = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).custom_flags(nix::libc::O_NOFOLLOW).open(path);
+    match res {
+      Ok(mut file) => {
+        file.write_all(process::id().to_string().as_bytes())?;
+        return Ok(Ok(()));
+      },
+      Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => match read_pidfile(path) {
+        Some(pid) => return Ok(Err(pid)),
+        None => fs::remove_file(path)?,
+      },
+      Err(err) => return Err(err.into()),
+    }
+  }
+  Err(error::Error::UsageError(format!("Could not create the PID file: {}", path.display())))
+}
+
+// The process ID recorded in a file, when it's that of a psctl which is
+// still running.
+fn read_pidfile(path: &path::Path) -> Option<i32> {
+  use std::ffi::OsStr;
+  use std::os::unix::ffi::OsStrExt;
+  let pid: i32 = fs::read_to_string(path).ok()?.trim().parse().ok().filter(|e| *e > 0)?;
+  let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+  let prog = |arg: &[u8]| path::Path::new(OsStr::from_bytes(arg)).file_name().map(|e| e.to_owned());
+  let name = prog(cmdline.split(|e| *e == 0).next()?)?;
+  let own = std::env::args_os().next().and_then(|e| prog(e.as_bytes()));
+  (name == "psctl" || Some(&name) == own.as_ref()).then_some(pid)
+}
+
+fn schema(sub: &config::SchemaOptions) -> Result<i32, error::Error> {
+  let data = match serde_json::to_string_pretty(&schema::taskfile()) {
+    Ok(data) => data + "\n",
+    Err(err) => return Err(error::Error::IOError(err.into())),
+  };
+  match &sub.output {
+    Some(output) => fs::write(output, data)?,
+    None => print!("{}", data),
   }
-  Ok(spec.with_tasks(tasks))
+  Ok(0)
 }
@@ -0,0 +1,355 @@
+use std::io;
+use std::fs;
+use std::fmt;
+use std::path;
+use std::result;
+use std::io::Write;
+
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+
+use super::error;
+use super::limits::Bytes;
+use super::Result;
+
+/// The cgroup a task runs in, and the limits that apply to it.
+#[derive(PartialEq, Eq, Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct Cgroup {
+  /// The most memory the task can use, like '512M', beyond which it is killed by the OOM killer.
+  #[serde(skip_serializing_if="Option::is_none")]
+  memory: Option<Bytes>,
+  /// How much CPU time the task can use, in CPUs, like 0.5 or 2.
+  #[serde(skip_serializing_if="Option::is_none")]
+  cpu: Option<Cpus>,
+}
+
+impl Cgroup {
+  /// Check that the limits are sensible.
+  pub fn validate(&self, key: &str) -> Result<()> {
+    if let Some(cpu) = self.cpu {
+      if cpu.0 < 10 {
+        return Err(error::ExecError::new(&format!("Cgroup CPU limit of {} is too low; the lowest limit is 0.01: {}", cpu, key)).into());
+      }
+    }
+    Ok(())
+  }
+
+  // The controllers the cgroup needs.
+  fn controllers(&self) -> Vec<&'static str> {
+    let mut res: Vec<&'static str> = Vec::new();
+    if self.memory.is_some() {
+      res.push("memory");
+    }
+    if self.cpu.is_some() {
+      res.push("cpu");
+    }
+    res
+  }
+}
+
+/// The part of the cgroup v2 hierarchy delegated to psctl. Each psctl has a
+/// cgroup of its own in it, named for its process ID, in which every task
+/// that asks for one gets its own cgroup; other instances of psctl which
+/// share the delegated cgroup are left alone.
+pub struct Hierarchy {
+  base: path::PathBuf,
+  own: path::PathBuf,
+  moved: bool,
+  controllers: Vec<String>,
+}
+
+impl Hierarchy {
+  /// Take over the cgroup psctl runs in, which must be delegated to it.
+  /// Controllers can only be enabled for the tasks' cgroups when no process
+  /// is in the cgroup itself, so psctl moves into a cgroup of its own.
+  pub fn new() -> Result<Self> {
+    if !cfg!(target_os = "linux") {
+      return Err(error::ExecError::new("Running tasks in cgroups is only supported on Linux").into());
+    }
+    let base = match current() {
+      Some(base) => base,
+      None => return Err(error::ExecError::new("Could not find the cgroup psctl runs in; cgroup v2 is required").into()),
+    };
+    if nix::unistd::access(&base.join("cgroup.procs"), nix::unistd::AccessFlags::W_OK).is_err() {
+      return Err(error::ExecError::new(&format!("The cgroup psctl runs in is not delegated to it: {}", base.display())).into());
+    }
+    sweep(&base);
+    let own = base.join(format!("psctl-{}", std::process::id()));
+    fs::create_dir(&own)?;
+    let mut res = Self{
+      base,
+      own,
+      moved: false,
+      controllers: Vec::new(),
+    };
+    let avail = fs::read_to_string(res.base.join("cgroup.controllers"))?;
+    let avail: Vec<&str> = avail.split_whitespace().filter(|e| *e == "memory" || *e == "cpu").collect();
+    if !avail.is_empty() {
+      let enable = avail.iter().map(|e| format!("+{}", e)).collect::<Vec<String>>().join(" ");
+      if write(&res.base.join("cgroup.subtree_control"), &enable).is_err() {
+        let leaf = res.own.join("psctl");
+        fs::create_dir(&leaf)?;
+        write(&leaf.join("cgroup.procs"), &std::process::id().to_string())?;
+        res.moved = true;
+        let _ = write(&res.base.join("cgroup.subtree_control"), &enable); // other processes in the cgroup still prevent this
+      }
+      let avail = fs::read_to_string(res.own.join("cgroup.controllers"))?;
+      let enable = avail.split_whitespace().filter(|e| *e == "memory" || *e == "cpu").map(|e| format!("+{}", e)).collect::<Vec<String>>().join(" ");
+      if !enable.is_empty() {
+        write(&res.own.join("cgroup.subtree_control"), &enable)?;
+      }
+    }
+    let controllers = fs::read_to_string(res.own.join("cgroup.subtree_control"))?;
+    res.controllers = controllers.split_whitespace().map(|e| e.to_owned()).collect();
+    Ok(res)
+  }
+
+  // The cgroup of a task.
+  fn group(&self, key: &str) -> path::PathBuf {
+    self.own.join(format!("task-{}", key.replace('/', "_")))
+  }
+
+  /// Create a fresh cgroup for a task, replacing any that is left from
+  /// running it before, and apply its limits.
+  #[allow(clippy::redundant_field_names)]
+  pub async fn create(&self, key: &str, conf: &Cgroup) -> Result<Group> {
+    if let Some(name) = conf.controllers().into_iter().find(|e| !self.controllers.iter().any(|c| c == e)) {
+      return Err(error::ExecError::new(&format!("The {} controller is not available to psctl's cgroup: {}: {}", name, self.base.display(), key)).into());
+    }
+    self.remove(key).await;
+    let dir = self.group(key);
+    if let Err(err) = fs::create_dir(&dir) {
+      return Err(error::ExecError::new(&format!("Could not create cgroup: {}; because: {}: {}", dir.display(), err, key)).into());
+    }
+    if let Some(memory) = conf.memory {
+      write(&dir.join("memory.max"), &memory.to_string())?;
+      write(&dir.join("memory.oom.group"), "1")?; // an OOM kill takes the whole task down
+    }
+    if let Some(cpu) = conf.cpu {
+      write(&dir.join("cpu.max"), &format!("{} {}", cpu.0 * CPU_PERIOD / 1000, CPU_PERIOD))?;
+    }
+    let procs = fs::OpenOptions::new().write(true).open(dir.join("cgroup.procs"))?;
+    Ok(Group{
+      procs: procs,
+    })
+  }
+
+  /// Kill every process in a task's cgroup, including those which left its
+  /// process group.
+  pub fn kill(&self, key: &str) {
+    let _ = write(&self.group(key).join("cgroup.kill"), "1");
+  }
+
+  /// Kill every process in a task's cgroup and remove it, once it's empty.
+  pub async fn remove(&self, key: &str) {
+    let dir = self.group(key);
+    if !dir.is_dir() {
+      return;
+    }
+    self.kill(key);
+    for _ in 0..100 {
+      if !populated(&dir) {
+        break;
+      }
+      tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let _ = fs::remove_dir(&dir);
+  }
+
+  /// Whether a process in a task's cgroup was killed by the OOM killer.
+  pub fn oom_killed(&self, key: &str) -> bool {
+    let events = fs::read_to_string(self.group(key).join("memory.events")).unwrap_or_default();
+    events.lines().filter_map(|e| e.strip_prefix("oom_kill ")).any(|e| e.trim() != "0")
+  }
+
+  /// Remove psctl's own cgroup, once the tasks have stopped, along with the
+  /// cgroups of tasks that could not be spawned. When psctl moved into a
+  /// cgroup of its own, it can't remove that while it's in it; it is left
+  /// until the next psctl that shares the delegated cgroup sweeps it up.
+  pub fn close(&self) {
+    if let Ok(dir) = fs::read_dir(&self.own) {
+      for ent in dir.flatten().filter(|e| e.file_name().to_string_lossy().starts_with("task-")) {
+        let _ = fs::remove_dir(ent.path());
+      }
+    }
+    if !self.moved {
+      let _ = fs::remove_dir(&self.own);
+    }
+  }
+}
+
+// Remove the cgroups left by instances of psctl which have exited, and one
+// left by an earlier process with psctl's own ID. Only those that are empty
+// are removed, so nothing is killed.
+fn sweep(base: &path::Path) {
+  let dir = match fs::read_dir(base) {
+    Ok(dir) => dir,
+    Err(_) => return,
+  };
+  for ent in dir.flatten() {
+    let pid: i32 = match ent.file_name().to_string_lossy().strip_prefix("psctl-").and_then(|e| e.parse().ok()) {
+      Some(pid) => pid,
+      None => continue,
+    };
+    let alive = pid as u32 != std::process::id() && nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None).is_ok();
+    if alive || populated(&ent.path()) {
+      continue;
+    }
+    if let Ok(sub) = fs::read_dir(ent.path()) {
+      for e in sub.flatten().filter(|e| e.path().is_dir()) {
+        let _ = fs::remove_dir(e.path());
+      }
+    }
+    let _ = fs::remove_dir(ent.path());
+  }
+}
+
+/// A task's cgroup, which it is placed in when it's spawned.
+pub struct Group {
+  procs: fs::File,
+}
+
+impl Group {
+  /// Place a command in the cgroup before it runs, so that everything it
+  /// starts is there too.
+  pub fn attach(self, cmd: &mut std::process::Command) {
+    use std::os::unix::process::CommandExt;
+    let procs = self.procs;
+    // Writing '0' moves the writing process; only the write is made between
+    // fork and exec.
+    unsafe {
+      cmd.pre_exec(move || (&procs).write_all(b"0"));
+    }
+  }
+}
+
+// The period over which CPU time is limited, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+// Write a value to a cgroup interface file.
+fn write(path: &path::Path, val: &str) -> io::Result<()> {
+  fs::OpenOptions::new().write(true).open(path)?.write_all(val.as_bytes())
+}
+
+// Whether any process is left in a cgroup.
+fn populated(dir: &path::Path) -> bool {
+  let events = fs::read_to_string(dir.join("cgroup.events")).unwrap_or_default();
+  events.lines().any(|e| e == "populated 1")
+}
+
+// The directory of the cgroup v2 psctl runs in.
+fn current() -> Option<path::PathBuf> {
+  let cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+  let rel = cgroup.lines().find_map(|e| e.strip_prefix("0::"))?;
+  let mounts = fs::read_to_string("/proc/self/mountinfo").ok()?;
+  let (root, point) = mounts.lines().find_map(parse_mount)?;
+  let rel = rel.strip_prefix(&root).unwrap_or(rel);
+  match rel.trim_start_matches('/') {
+    "" => Some(point),
+    rel => Some(point.join(rel)),
+  }
+}
+
+// The root and mount point of a cgroup v2 mount, from a line of
+// '/proc/self/mountinfo'. Optional fields precede the filesystem type,
+// after a separating '-'.
+fn parse_mount(line: &str) -> Option<(String, path::PathBuf)> {
+  let (mount, fs) = line.split_once(" - ")?;
+  if fs.split_whitespace().next()? != "cgroup2" {
+    return None;
+  }
+  let mut fields = mount.split_whitespace().skip(3);
+  let root = fields.next()?.to_owned();
+  let point = fields.next()?;
+  Some((root, path::PathBuf::from(point)))
+}
+
+/// An amount of CPU time, in thousandths of a CPU.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Cpus(u64);
+
+impl fmt::Display for Cpus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0 as f64 / 1000.0)
+  }
+}
+
+impl Serialize for Cpus {
+  fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(self.0 as f64 / 1000.0)
+  }
+}
+
+impl<'de> Deserialize<'de> for Cpus {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+    struct CpusVisitor;
+
+    impl<'de> de::Visitor<'de> for CpusVisitor {
+      type Value = Cpus;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a positive number of CPUs, like 0.5 or 2")
+      }
+
+      fn visit_u64<E: de::Error>(self, v: u64) -> result::Result<Cpus, E> {
+        match v.checked_mul(1000) {
+          Some(v) if v > 0 => Ok(Cpus(v)),
+          _ => Err(E::invalid_value(de::Unexpected::Unsigned(v), &self)),
+        }
+      }
+
+      fn visit_i64<E: de::Error>(self, v: i64) -> result::Result<Cpus, E> {
+        match u64::try_from(v) {
+          Ok(v) => self.visit_u64(v),
+          Err(_) => Err(E::invalid_value(de::Unexpected::Signed(v), &self)),
+        }
+      }
+
+      fn visit_f64<E: de::Error>(self, v: f64) -> result::Result<Cpus, E> {
+        if !v.is_finite() || v <= 0.0 || v > u32::MAX as f64 {
+          return Err(E::invalid_value(de::Unexpected::Float(v), &self));
+        }
+        Ok(Cpus((v * 1000.0).round() as u64))
+      }
+    }
+
+    deserializer.deserialize_any(CpusVisitor)
+  }
+}
+
+impl JsonSchema for Cpus {
+  fn schema_name() -> std::borrow::Cow<'static, str> {
+    "Cpus".into()
+  }
+
+  fn json_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+      "description": "A number of CPUs, like 0.5 or 2.",
+      "type": "number",
+      "exclusiveMinimum": 0,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cgroup() {
+    let conf: Cgroup = serde_yaml::from_str("{memory: 512M, cpu: 1.5}").unwrap();
+    assert_eq!(Some(Bytes(512 << 20)), conf.memory);
+    assert_eq!(Some(Cpus(1500)), conf.cpu);
+    assert_eq!(vec!["memory", "cpu"], conf.controllers());
+    assert!(conf.validate("api").is_ok());
+    let conf: Cgroup = serde_yaml::from_str("{cpu: 2}").unwrap();
+    assert_eq!(Some(Cpus(2000)), conf.cpu);
+    assert!(serde_yaml::from_str::<Cgroup>("{cpu: 0}").is_err());
+    assert!(serde_yaml::from_str::<Cgroup>("{cpu: 0.001}").unwrap().validate("api").is_err());
+
+    assert_eq!(Some(("/".to_string(), path::PathBuf::from("/sys/fs/cgroup"))), parse_mount("35 24 0:30 / /sys/fs/cgroup rw,nosuid shared:9 - cgroup2 cgroup2 rw"));
+    assert_eq!(None, parse_mount("36 24 0:31 / /sys/fs/cgroup/memory rw - cgroup cgroup rw,memory"));
+  }
+
+}
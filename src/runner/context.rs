@@ -0,0 +1,201 @@
+use std::net;
+use std::result;
+use std::collections::HashMap;
+
+use super::error;
+use super::Process;
+use super::Result;
+
+/// The values tasks can refer to, which are only known once they are started:
+/// the ports allocated to them and the values they export. A task can refer
+/// to its own ports, as '${port.<name>}', and to the ports and exports of the
+/// tasks it depends on, as '${<task>.port.<name>}' and '${<task>.<name>}'.
+#[derive(Default)]
+pub struct Context {
+  ports: HashMap<String, Vec<(String, u16)>>,
+  exports: HashMap<String, HashMap<String, String>>,
+}
+
+impl Context {
+  /// Create a context in which every port is allocated as zero, which can be
+  /// used to check references without starting anything.
+  pub fn placeholder(procs: &[&Process]) -> Self {
+    let mut ctx = Self::default();
+    for proc in procs {
+      ctx.ports.insert(proc.key().to_owned(), proc.ports().iter().map(|e| (e.to_owned(), 0)).collect());
+      ctx.exports.insert(proc.key().to_owned(), proc.exports().keys().map(|e| (e.to_owned(), String::new())).collect());
+    }
+    ctx
+  }
+
+  /// Allocate the ports a task declares from those that are free. A task
+  /// that has already been allocated ports, because it is being restarted,
+  /// keeps them.
+  pub fn allocate(&mut self, spec: &Process) -> Result<()> {
+    if spec.ports().is_empty() || self.ports.contains_key(spec.key()) {
+      return Ok(());
+    }
+    let taken: Vec<u16> = self.ports.values().flatten().map(|(_, port)| *port).collect();
+    let mut held: Vec<net::TcpListener> = Vec::new(); // hold each port until all are allocated so none is allocated twice
+    let mut ports: Vec<(String, u16)> = Vec::new();
+    for name in spec.ports() {
+      loop {
+        let sock = net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0))?;
+        let port = sock.local_addr()?.port();
+        held.push(sock);
+        if !taken.contains(&port) {
+          ports.push((name.to_owned(), port));
+          break;
+        }
+      }
+    }
+    self.ports.insert(spec.key().to_owned(), ports);
+    Ok(())
+  }
+
+  /// Set the values a task exports, once they are available.
+  pub fn export(&mut self, spec: &Process, values: HashMap<String, String>) {
+    self.exports.insert(spec.key().to_owned(), values);
+  }
+
+  /// Forget the ports and exports of a task that has been stopped for good,
+  /// or that is restarted because it has changed.
+  pub fn forget(&mut self, spec: &Process) {
+    self.ports.remove(spec.key());
+    self.exports.remove(spec.key());
+  }
+
+  /// Resolve the references in a task and provide it with its ports and those
+  /// of its dependencies as environment variables: its own ports are named
+  /// like 'PORT_HTTP', or 'PORT' when it has only one, and those of a
+  /// dependency like 'API_PORT_HTTP'.
+  pub fn resolve(&self, spec: &Process) -> Result<Process> {
+    let mut vars: HashMap<String, String> = HashMap::new();
+    let mut env: HashMap<String, String> = HashMap::new();
+    if let Some(ports) = self.ports.get(spec.key()) {
+      for (name, port) in ports {
+        vars.insert(format!("port.{}", name), port.to_string());
+        env.insert(format!("PORT_{}", env_name(name)), port.to_string());
+      }
+      if let [(_, port)] = ports.as_slice() {
+        env.insert("PORT".to_string(), port.to_string());
+      }
+    }
+    for dep in spec.deps() {
+      if let Some(ports) = self.ports.get(dep) {
+        for (name, port) in ports {
+          vars.insert(format!("{}.port.{}", dep, name), port.to_string());
+          env.insert(format!("{}_PORT_{}", env_name(dep), env_name(name)), port.to_string());
+        }
+      }
+      if let Some(values) = self.exports.get(dep) {
+        for (name, val) in values {
+          vars.insert(format!("{}.{}", dep, name), val.to_owned());
+        }
+      }
+    }
+    let res = match spec.interpolate(&vars) {
+      Ok(res) => res,
+      Err(name) => return Err(match replicated(spec, &name) {
+        Some(task) => error::ExecError::new(&format!("{}: Reference is to a replicated task: ${{{}}}; refer to one of its replicas instead, like ${{{}.1{}}}", spec.key(), name, task, &name[task.len()..])),
+        None => error::ExecError::new(&format!("{}: Reference is not defined: ${{{}}}; a task can refer to its own ports, and the ports and exports of the tasks it depends on", spec.key(), name)),
+      }.into()),
+    };
+    Ok(res.with_env(&env))
+  }
+}
+
+// The replicated task a reference is to, if it is to one the task depends
+// on. A dependency on a replicated task is one on each of its replicas, so
+// which replica the reference is to is ambiguous.
+fn replicated<'a>(spec: &'a Process, name: &str) -> Option<&'a str> {
+  if spec.deps().iter().any(|e| name.starts_with(&format!("{}.", e))) {
+    return None;
+  }
+  spec.deps().iter()
+    .filter_map(|e| e.rsplit_once('.').filter(|(_, n)| n.parse::<u32>().is_ok()).map(|(task, _)| task))
+    .find(|task| name.starts_with(&format!("{}.", task)))
+}
+
+// The name of a task or port as it's used in an environment variable.
+fn env_name(name: &str) -> String {
+  name.chars().map(|c| match c.is_ascii_alphanumeric() {
+    true  => c.to_ascii_uppercase(),
+    false => '_',
+  }).collect()
+}
+
+/// Replace references like '${port.http}' in text with their values.
+/// References are always dotted, so that a shell parameter like '${HOME}' is
+/// left alone. When a reference is not defined, its name is returned.
+pub fn interpolate(text: &str, vars: &HashMap<String, String>) -> result::Result<String, String> {
+  let mut res = String::new();
+  let mut rest = text;
+  while let Some(start) = rest.find("${") {
+    let after = &rest[start + 2..];
+    let name = match after.find('}') {
+      Some(end) => &after[..end],
+      None => break,
+    };
+    res.push_str(&rest[..start]);
+    if name.contains('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+      match vars.get(name) {
+        Some(val) => res.push_str(val),
+        None => return Err(name.to_owned()),
+      }
+    }else{
+      res.push_str(&rest[start..start + 3 + name.len()]);
+    }
+    rest = &after[name.len() + 1..];
+  }
+  res.push_str(rest);
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_interpolate() {
+    let vars: HashMap<String, String> = [("port.http", "8080"), ("api.port.grpc", "9090")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    assert_eq!(Ok("http://localhost:8080/health".to_string()), interpolate("http://localhost:${port.http}/health", &vars));
+    assert_eq!(Ok("--api 9090 --home ${HOME} $PATH".to_string()), interpolate("--api ${api.port.grpc} --home ${HOME} $PATH", &vars));
+    assert_eq!(Ok("unterminated ${port.http".to_string()), interpolate("unterminated ${port.http", &vars));
+    assert_eq!(Err("db.port.sql".to_string()), interpolate("${db.port.sql}", &vars));
+  }
+
+  #[test]
+  fn test_resolve() {
+    let mut api = Process::new(Some("api"), "api --port ${port.http}", vec![], Some("http://localhost:${port.http}/"));
+    api.ports = vec!["http".to_string()];
+    let web = Process::new(Some("web"), "web --api localhost:${api.port.http}", vec!["api"], None);
+
+    let mut ctx = Context::default();
+    ctx.allocate(&api).unwrap();
+    let port = ctx.ports["api"][0].1;
+    assert!(port > 0);
+
+    let res = ctx.resolve(&api).unwrap();
+    assert_eq!(format!("api --port {}", port), res.command());
+    assert_eq!(format!("http://localhost:{}/", port), res.checks()[0].url());
+    assert_eq!(Some(&port.to_string()), res.env.get("PORT"));
+    assert_eq!(Some(&port.to_string()), res.env.get("PORT_HTTP"));
+
+    let res = ctx.resolve(&web).unwrap();
+    assert_eq!(format!("web --api localhost:{}", port), res.command());
+    assert_eq!(Some(&port.to_string()), res.env.get("API_PORT_HTTP"));
+
+    let orphan = Process::new(Some("orphan"), "web --api localhost:${api.port.http}", vec![], None);
+    assert!(ctx.resolve(&orphan).is_err());
+
+    ctx.export(&api, [("token".to_string(), "secret".to_string())].into_iter().collect());
+    let cli = Process::new(Some("cli"), "cli --token ${api.token}", vec!["api"], None);
+    assert_eq!("cli --token secret", ctx.resolve(&cli).unwrap().command());
+
+    let cli = Process::new(Some("cli"), "cli --token ${worker.token}", vec!["worker.1", "worker.2"], None);
+    let err = ctx.resolve(&cli).err().unwrap().to_string();
+    assert!(err.contains("replicated task") && err.contains("${worker.1.token}"), "{}", err);
+  }
+
+}
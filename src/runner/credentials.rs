@@ -0,0 +1,168 @@
+use std::io;
+
+use nix::libc;
+use nix::unistd::{self, Gid, Uid};
+
+use super::error;
+use super::Result;
+
+/// Who a task runs as, and the mask its files are created with, resolved
+/// from the names or ids that describe them.
+#[derive(Debug, Default)]
+pub struct Credentials {
+  user: Option<unistd::User>,
+  uid: Option<Uid>,
+  gid: Option<Gid>,
+  groups: Option<Vec<Gid>>,
+  umask: Option<libc::mode_t>,
+}
+
+impl Credentials {
+  /// Resolve a task's user, group and supplementary groups, by name or id,
+  /// and parse its umask. Running as another user or group requires root.
+  pub fn resolve(key: &str, user: Option<&str>, group: Option<&str>, groups: &[String], umask: Option<&str>) -> Result<Self> {
+    let mut res = Self::default();
+    if let Some(name) = user {
+      let entry = match name.parse::<u32>() {
+        Ok(id) => unistd::User::from_uid(Uid::from_raw(id)).map_err(io::Error::from)?,
+        Err(_) => unistd::User::from_name(name).map_err(io::Error::from)?,
+      };
+      match (&entry, name.parse::<u32>()) {
+        (Some(entry), _) => {
+          res.uid = Some(entry.uid);
+          res.gid = Some(entry.gid);
+        },
+        (None, Ok(id)) => res.uid = Some(Uid::from_raw(id)), // a user that has no entry runs with its id, and the group it's given
+        (None, Err(_)) => return Err(error::ExecError::new(&format!("Unknown user: {}: {}", name, key)).into()),
+      }
+      res.user = entry;
+    }
+    if let Some(name) = group {
+      res.gid = Some(resolve_group(key, name)?);
+    }
+    if res.uid.is_some() && res.gid.is_none() {
+      return Err(error::ExecError::new(&format!("A user that has no entry requires a group: {}: {}", user.unwrap_or_default(), key)).into());
+    }
+    if !groups.is_empty() {
+      res.groups = Some(groups.iter().map(|e| resolve_group(key, e)).collect::<Result<Vec<Gid>>>()?);
+    }
+    if let Some(umask) = umask {
+      match libc::mode_t::from_str_radix(umask, 8) {
+        Ok(mask) if mask <= 0o777 => res.umask = Some(mask),
+        _ => return Err(error::ExecError::new(&format!("Invalid umask: {}; a umask is an octal mode like '027': {}", umask, key)).into()),
+      }
+    }
+
+    if !unistd::geteuid().is_root() {
+      if res.uid.is_some_and(|e| e != unistd::geteuid()) {
+        return Err(error::ExecError::new(&format!("Running as another user requires root: {}", key)).into());
+      }
+      if res.gid.is_some_and(|e| e != unistd::getegid()) || res.groups.is_some() {
+        return Err(error::ExecError::new(&format!("Running as another group requires root: {}", key)).into());
+      }
+      return Ok(res);
+    }
+    // A named user keeps the supplementary groups it's a member of, as it
+    // would with su, unless the task lists them.
+    if let (Some(user), Some(gid), None) = (&res.user, res.gid, &res.groups) {
+      let name = std::ffi::CString::new(user.name.as_str()).map_err(io::Error::from)?;
+      res.groups = Some(unistd::getgrouplist(&name, gid).map_err(io::Error::from)?);
+    }
+    Ok(res)
+  }
+
+  /// Apply the credentials to a command, before it runs. This comes after
+  /// anything else that needs psctl's privileges.
+  pub fn apply(self, cmd: &mut std::process::Command) {
+    use std::os::unix::process::CommandExt;
+    if let Some(user) = &self.user {
+      cmd.env("HOME", &user.dir);
+      cmd.env("USER", &user.name);
+      cmd.env("LOGNAME", &user.name);
+    }
+    // The supplementary groups are replaced whenever the user or group is
+    // changed, so that none of psctl's own are kept. A user always comes
+    // with a group, so the groups are never left as psctl's; without root,
+    // they're only psctl's own anyway.
+    let groups: Option<Vec<libc::gid_t>> = match (&self.groups, self.gid) {
+      (Some(groups), _) => Some(groups.iter().map(|e| e.as_raw()).collect()),
+      (None, Some(gid)) if unistd::geteuid().is_root() && (self.uid.is_some() || self.gid != Some(unistd::getegid())) => Some(vec![gid.as_raw()]),
+      (None, _) => None,
+    };
+    let (uid, gid, umask) = (self.uid, self.gid, self.umask);
+    if uid.is_none() && gid.is_none() && groups.is_none() && umask.is_none() {
+      return;
+    }
+    let apply = move || -> io::Result<()> {
+      if let Some(groups) = &groups {
+        if unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) } != 0 {
+          return Err(io::Error::last_os_error());
+        }
+      }
+      if let Some(gid) = gid {
+        unistd::setgid(gid)?;
+      }
+      if let Some(uid) = uid {
+        unistd::setuid(uid)?;
+      }
+      if let Some(mask) = umask {
+        unsafe { libc::umask(mask); }
+      }
+      Ok(())
+    };
+    // Only async-signal-safe calls are made between fork and exec.
+    unsafe { cmd.pre_exec(apply); }
+  }
+}
+
+// Resolve a group by name or id.
+fn resolve_group(key: &str, name: &str) -> Result<Gid> {
+  if let Ok(id) = name.parse::<u32>() {
+    return Ok(Gid::from_raw(id));
+  }
+  match unistd::Group::from_name(name).map_err(io::Error::from)? {
+    Some(group) => Ok(group.gid),
+    None => Err(error::ExecError::new(&format!("Unknown group: {}: {}", name, key)).into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn test_credentials() {
+    let uid = unistd::geteuid().to_string();
+    let creds = Credentials::resolve("api", Some(&uid), None, &[], Some("027")).unwrap();
+    assert_eq!(Some(unistd::geteuid()), creds.uid);
+    assert_eq!(Some(0o027), creds.umask);
+    assert!(Credentials::resolve("api", None, None, &[], Some("0999")).is_err());
+    assert!(Credentials::resolve("api", Some("no-such-user-psctl"), None, &[], None).is_err());
+    assert!(Credentials::resolve("api", None, Some("no-such-group-psctl"), &[], None).is_err());
+
+    // a user that has no entry needs a group, or it would keep psctl's
+    let unknown = (60000..u32::MAX).find(|e| unistd::User::from_uid(Uid::from_raw(*e)).ok().flatten().is_none()).unwrap().to_string();
+    let gid = unistd::getegid().to_string();
+    assert!(Credentials::resolve("api", Some(&unknown), None, &[], None).is_err());
+    if unistd::geteuid().is_root() {
+      let creds = Credentials::resolve("api", Some(&unknown), Some(&gid), &[], None).unwrap();
+      assert!(creds.user.is_none());
+      assert_eq!(Some(unistd::getegid()), creds.gid);
+    }
+
+    // a named user keeps the groups it's a member of, unless others are listed
+    let member = fs::read_to_string("/etc/group").unwrap_or_default().lines().find_map(|e| {
+      let fields: Vec<&str> = e.split(':').collect();
+      let user = fields.get(3)?.split(',').find(|e| unistd::User::from_name(e).ok().flatten().is_some())?;
+      Some((user.to_owned(), Gid::from_raw(fields.get(2)?.parse().ok()?)))
+    });
+    if let (true, Some((user, group))) = (unistd::geteuid().is_root(), member) {
+      let creds = Credentials::resolve("api", Some(&user), None, &[], None).unwrap();
+      assert!(creds.groups.unwrap().contains(&group));
+      let creds = Credentials::resolve("api", Some(&user), None, std::slice::from_ref(&gid), None).unwrap();
+      assert_eq!(Some(vec![unistd::getegid()]), creds.groups);
+    }
+  }
+
+}
@@ -36,6 +36,7 @@ impl fmt::Display for DependencyError {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
   IOError(io::Error),
   WaiterError(waiter::error::Error),
@@ -43,6 +44,7 @@ pub enum Error {
   DependencyError(DependencyError),
   CanceledError,
   NeverInitializedError(String),
+  EarlyExitError(String, String, String),
 }
 
 impl From<io::Error> for Error {
@@ -78,6 +80,7 @@ impl fmt::Display for Error {
       Self::DependencyError(err) => err.fmt(f),
       Self::CanceledError => write!(f, "Canceled"),
       Self::NeverInitializedError(key) => write!(f, "{}: exited before it became available", key),
+      Self::EarlyExitError(key, main, desc) => write!(f, "{}: {} before {} finished; exiting with status {}", key, desc, main, super::EARLY_EXIT_CODE),
     }
   }
 }
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::result;
+use std::process::ExitStatus;
+use std::os::unix::process::ExitStatusExt;
+
+use nix::sys::signal::Signal;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+
+use super::error;
+use super::Process;
+use super::Result;
+
+/// When the pod finishes, and which tasks its exit status is taken from.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub enum ExitPolicy {
+  /// Finish when the first task exits, with its status.
+  #[default]
+  FirstExit,
+  /// Finish once every task has exited, with the status of the first task
+  /// that failed.
+  WaitForAll,
+  /// Finish once the named tasks have exited, with the status of the first
+  /// of them that failed.
+  WaitFor(Vec<String>),
+}
+
+/// The tasks to wait for before the pod finishes.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct WaitFor {
+  /// The names of the tasks to wait for.
+  #[serde(rename = "wait-for")]
+  wait_for: Vec<String>,
+}
+
+impl ExitPolicy {
+  // Whether the policy waits for a task. A name refers to every replica of
+  // a replicated task.
+  fn covers(&self, key: &str) -> bool {
+    match self {
+      Self::FirstExit | Self::WaitForAll => true,
+      Self::WaitFor(names) => names.iter().any(|name| names_task(name, key)),
+    }
+  }
+
+  /// Whether a task ends the pod when it exits, which is the case for every
+  /// task that isn't explicitly non-critical when the first exit does.
+  pub fn critical(&self, spec: &Process) -> bool {
+    spec.critical.unwrap_or(*self == Self::FirstExit)
+  }
+
+  /// Whether the pod is finished, given the tasks that have exited.
+  pub fn finished(&self, procs: &[&Process], exited: &[(String, ExitStatus)]) -> bool {
+    procs.iter().filter(|e| self.covers(e.key())).all(|e| exited.iter().any(|(key, _)| key == e.key()))
+  }
+
+  /// The exit code of a finished pod, which is that of the first task it
+  /// waits for that failed, in the order they exited.
+  pub fn code(&self, exited: &[(String, ExitStatus)]) -> i32 {
+    exited.iter().filter(|(key, _)| self.covers(key)).map(|(_, stat)| code(stat)).find(|e| *e != 0).unwrap_or_default()
+  }
+
+  /// Check that the tasks a policy waits for are defined.
+  pub fn validate(&self, procs: &[&Process]) -> Result<()> {
+    if let Self::WaitFor(names) = self {
+      if names.is_empty() {
+        return Err(error::ExecError::new("Exit policy must name the tasks to wait for").into());
+      }
+      if let Some(name) = names.iter().find(|name| !procs.iter().any(|e| names_task(name, e.key()))) {
+        return Err(error::ExecError::new(&format!("Exit policy waits for an undefined task: {}", name)).into());
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Whether a name refers to a task, or to one of its replicas, which are
+/// named like '<name>.<n>'.
+pub fn names_task(name: &str, key: &str) -> bool {
+  if key == name {
+    return true;
+  }
+  match key.strip_prefix(name).and_then(|e| e.strip_prefix('.')) {
+    Some(n) => !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()),
+    None => false,
+  }
+}
+
+impl Serialize for ExitPolicy {
+  fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+    match self {
+      Self::FirstExit => serializer.serialize_str("first-exit"),
+      Self::WaitForAll => serializer.serialize_str("wait-for-all"),
+      Self::WaitFor(names) => WaitFor{wait_for: names.clone()}.serialize(serializer),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for ExitPolicy {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+    struct ExitPolicyVisitor;
+
+    impl<'de> de::Visitor<'de> for ExitPolicyVisitor {
+      type Value = ExitPolicy;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'first-exit', 'wait-for-all' or an object listing the tasks to wait for")
+      }
+
+      fn visit_str<E: de::Error>(self, v: &str) -> result::Result<ExitPolicy, E> {
+        match v {
+          "first-exit" => Ok(ExitPolicy::FirstExit),
+          "wait-for-all" => Ok(ExitPolicy::WaitForAll),
+          _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+        }
+      }
+
+      fn visit_map<M: de::MapAccess<'de>>(self, m: M) -> result::Result<ExitPolicy, M::Error> {
+        let spec = WaitFor::deserialize(de::value::MapAccessDeserializer::new(m))?;
+        Ok(ExitPolicy::WaitFor(spec.wait_for))
+      }
+    }
+
+    deserializer.deserialize_any(ExitPolicyVisitor)
+  }
+}
+
+// A policy is described either by its name or, when it waits for particular
+// tasks, an object that lists them.
+impl JsonSchema for ExitPolicy {
+  fn schema_name() -> std::borrow::Cow<'static, str> {
+    "ExitPolicy".into()
+  }
+
+  fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+      "anyOf": [
+        {
+          "type": "string",
+          "enum": ["first-exit", "wait-for-all"],
+          "description": "Finish when the first task exits, with its status; or once every task has exited, with the status of the first task that failed.",
+        },
+        gen.subschema_for::<WaitFor>(),
+      ],
+    })
+  }
+}
+
+/// The exit code of a task, which is 128 plus the signal that killed it when
+/// it didn't exit on its own, as it is in a shell.
+pub fn code(stat: &ExitStatus) -> i32 {
+  match (stat.code(), stat.signal()) {
+    (Some(code), _) => code,
+    (None, Some(sig)) => 128 + sig,
+    (None, None) => 1,
+  }
+}
+
+/// Describe how a task exited.
+pub fn describe(stat: &ExitStatus) -> String {
+  match (stat.code(), stat.signal().map(Signal::try_from)) {
+    (Some(code), _) => format!("exited with status {}", code),
+    (None, Some(Ok(sig))) => format!("killed by {} ({})", sig, code(stat)),
+    (None, _) => format!("terminated ({})", code(stat)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_exit_policy() {
+    assert_eq!(ExitPolicy::WaitForAll, serde_yaml::from_str("wait-for-all").unwrap());
+    assert_eq!(ExitPolicy::WaitFor(vec!["test".to_string()]), serde_yaml::from_str("{wait-for: [test]}").unwrap());
+    assert!(serde_yaml::from_str::<ExitPolicy>("wait-for-some").is_err());
+
+    let api = Process::new(Some("api"), "api", vec![], None);
+    let worker = Process::new(Some("worker.2"), "worker", vec![], None);
+    let policy = ExitPolicy::WaitFor(vec!["worker".to_string()]);
+    assert!(policy.validate(&[&api, &worker]).is_ok());
+    assert!(ExitPolicy::WaitFor(vec!["work".to_string()]).validate(&[&api, &worker]).is_err());
+
+    let failed = ExitStatus::from_raw(3 << 8);
+    let killed = ExitStatus::from_raw(11); // SIGSEGV
+    assert_eq!(3, code(&failed));
+    assert_eq!(139, code(&killed));
+    assert_eq!("killed by SIGSEGV (139)", describe(&killed));
+
+    let exited = vec![("api".to_string(), failed)];
+    assert!(!policy.finished(&[&api, &worker], &exited));
+    assert_eq!(0, policy.code(&exited));
+    let exited = vec![("api".to_string(), failed), ("worker.2".to_string(), killed)];
+    assert!(policy.finished(&[&api, &worker], &exited));
+    assert_eq!(139, policy.code(&exited));
+    assert_eq!(3, ExitPolicy::WaitForAll.code(&exited));
+  }
+
+}
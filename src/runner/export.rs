@@ -0,0 +1,166 @@
+use std::fs;
+use std::time;
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use super::error;
+use super::Process;
+use super::Result;
+
+/// A value a task exports to the tasks that depend on it. The value is either
+/// captured from the task's output or read from a file it writes.
+#[derive(PartialEq, Eq, Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct Export {
+  /// A regular expression matched against each line of output; the value is the first capture group, or the whole match if there is none.
+  #[serde(skip_serializing_if="Option::is_none")]
+  pattern: Option<String>,
+  /// A file, relative to the task's directory, whose contents are the value; it must be written after the task starts.
+  #[serde(skip_serializing_if="Option::is_none")]
+  file: Option<String>,
+}
+
+impl Export {
+  /// Check that an export describes exactly one source, which is valid.
+  pub fn validate(&self, key: &str, name: &str) -> Result<()> {
+    match (&self.pattern, &self.file) {
+      (Some(pattern), None) => match Regex::new(pattern) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(error::ExecError::new(&format!("{}: Export '{}' has an invalid pattern: {}", key, name, err)).into()),
+      },
+      (None, Some(_)) => Ok(()),
+      _ => Err(error::ExecError::new(&format!("{}: Export '{}' must define either 'pattern' or 'file'", key, name)).into()),
+    }
+  }
+}
+
+/// Captures the values a task exports from its output. The first line that
+/// matches an export's pattern provides its value.
+#[derive(Clone)]
+pub struct Capture {
+  patterns: Arc<Vec<(String, Regex)>>,
+  values: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Capture {
+  pub fn new(exports: &BTreeMap<String, Export>) -> Result<Self> {
+    let mut patterns: Vec<(String, Regex)> = Vec::new();
+    for (name, export) in exports {
+      if let Some(pattern) = &export.pattern {
+        match Regex::new(pattern) {
+          Ok(pattern) => patterns.push((name.to_owned(), pattern)),
+          Err(err) => return Err(error::ExecError::new(&format!("Export '{}' has an invalid pattern: {}", name, err)).into()),
+        }
+      }
+    }
+    Ok(Self{
+      patterns: Arc::new(patterns),
+      values: Arc::new(Mutex::new(HashMap::new())),
+    })
+  }
+
+  /// Match a line of output against the patterns which haven't matched yet.
+  pub fn scan(&self, line: &str) {
+    if self.patterns.is_empty() {
+      return;
+    }
+    let mut values = self.values.lock().expect("Capture is poisoned");
+    for (name, pattern) in self.patterns.iter() {
+      if values.contains_key(name) {
+        continue;
+      }
+      if let Some(caps) = pattern.captures(line) {
+        if let Some(val) = caps.get(1).or_else(|| caps.get(0)) {
+          values.insert(name.to_owned(), val.as_str().to_owned());
+        }
+      }
+    }
+  }
+
+  fn get(&self, name: &str) -> Option<String> {
+    self.values.lock().expect("Capture is poisoned").get(name).cloned()
+  }
+}
+
+// The value of an export, if it is available yet. A file provides a value
+// once it has been written since the task started, so that one left from a
+// previous run isn't used, and isn't empty; its contents are used without
+// any trailing whitespace.
+fn value(spec: &Process, capture: &Capture, name: &str, export: &Export, started: time::SystemTime) -> Option<String> {
+  match &export.file {
+    Some(file) => {
+      let path = spec.path(file);
+      if fs::metadata(&path).and_then(|e| e.modified()).ok()? < started {
+        return None;
+      }
+      match fs::read_to_string(path) {
+        Ok(data) if !data.trim_end().is_empty() => Some(data.trim_end().to_owned()),
+        _ => None,
+      }
+    },
+    None => capture.get(name),
+  }
+}
+
+/// Wait for the values a task exports to be available, for as long as the
+/// task is allowed to become available.
+pub async fn wait(spec: &Process, capture: &Capture, started: time::SystemTime) -> Result<HashMap<String, String>> {
+  let deadline = tokio::time::Instant::now() + spec.wait;
+  let started = started - time::Duration::from_millis(10); // file times are taken from a coarse clock
+  loop {
+    let values: HashMap<String, String> = spec.exports().iter().filter_map(|(name, e)| Some((name.to_owned(), value(spec, capture, name, e, started)?))).collect();
+    if values.len() == spec.exports().len() {
+      return Ok(values);
+    }
+    if tokio::time::Instant::now() >= deadline {
+      let missing: Vec<&str> = spec.exports().keys().filter(|e| !values.contains_key(*e)).map(|e| e.as_str()).collect();
+      return Err(error::ExecError::new(&format!("{}: Exported values were not available after {}: {}", spec.key(), humantime::format_duration(spec.wait), missing.join(", "))).into());
+    }
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_capture() {
+    let mut exports: BTreeMap<String, Export> = BTreeMap::new();
+    exports.insert("url".to_string(), Export{pattern: Some(r"listening on (\S+)".to_string()), file: None});
+    exports.insert("token".to_string(), Export{pattern: Some(r"[0-9a-f]{8}".to_string()), file: None});
+
+    let capture = Capture::new(&exports).unwrap();
+    capture.scan("starting up");
+    capture.scan("listening on http://localhost:8080/");
+    capture.scan("listening on http://localhost:9090/");
+    capture.scan("token: deadbeef");
+    assert_eq!(Some("http://localhost:8080/".to_string()), capture.get("url"));
+    assert_eq!(Some("deadbeef".to_string()), capture.get("token"));
+
+    assert!(Export{pattern: Some("(".to_string()), file: None}.validate("a", "b").is_err());
+    assert!(Export{pattern: None, file: None}.validate("a", "b").is_err());
+  }
+
+  #[test]
+  fn test_file() {
+    let dir = std::env::temp_dir().join(format!("psctl-test-export-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("token"), "deadbeef\n").unwrap();
+    let mut spec = Process::new(Some("api"), "./api", vec![], None);
+    spec.dir = Some(dir.to_string_lossy().into_owned());
+    let export = Export{pattern: None, file: Some("token".to_string())};
+    let capture = Capture::new(&BTreeMap::new()).unwrap();
+
+    let before = time::SystemTime::now() - time::Duration::from_secs(60);
+    assert_eq!(Some("deadbeef".to_string()), value(&spec, &capture, "token", &export, before));
+    let after = time::SystemTime::now() + time::Duration::from_secs(60);
+    assert_eq!(None, value(&spec, &capture, "token", &export, after)); // left from a previous run
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+use std::result;
+use std::process::Stdio;
+
+use colored::Colorize;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use tokio::process;
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::config;
+
+use super::error;
+use super::exit;
+use super::Origin;
+use super::Process;
+use super::Result;
+
+/// A command which is run when something happens to a task or to the pod.
+#[derive(PartialEq, Eq, Clone)]
+pub struct Hook {
+  origin: Origin,
+  run: String,
+  fatal: bool,
+}
+
+/// A hook, described as an object.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct HookSpec {
+  /// The command to run with a shell.
+  run: String,
+  /// Whether the hook failing is an error, which stops the pod; otherwise it is only reported.
+  #[serde(default)]
+  fatal: bool,
+}
+
+impl Hook {
+  /// Create a hook that runs a command.
+  pub fn new(run: &str) -> Self {
+    Self{
+      origin: Origin::unknown(),
+      run: run.to_owned(),
+      fatal: false,
+    }
+  }
+
+  /// The hook as it is defined by a particular taskfile, which a hook that
+  /// doesn't belong to a task runs in the directory of.
+  pub fn with_origin(&self, origin: Origin) -> Self {
+    let mut dup = self.clone();
+    dup.origin = origin;
+    dup
+  }
+
+  // The command that runs the hook. A task's hook runs with the task's shell,
+  // directory and environment.
+  fn command(&self, spec: Option<&Process>, env: &[(&str, String)]) -> process::Command {
+    let shell: Vec<&str> = match spec.and_then(|e| e.shell.as_deref()) {
+      Some(shell) => shell.split_whitespace().collect(),
+      None => vec!["sh"],
+    };
+    let mut cmd = process::Command::new(shell[0]);
+    cmd.args(&shell[1..]);
+    cmd.arg("-c").arg(&self.run);
+    let dir = match spec {
+      Some(spec) => spec.working_dir(),
+      None => self.origin.definition.as_ref().and_then(|e| e.parent()).map(|e| e.to_path_buf()),
+    };
+    if let Some(dir) = dir.filter(|e| !e.as_os_str().is_empty()) {
+      cmd.current_dir(dir);
+    }
+    if let Some(spec) = spec {
+      cmd.envs(&spec.env);
+    }
+    for (key, val) in env {
+      cmd.env(key, val);
+    }
+    cmd.stdin(Stdio::null());
+    cmd.kill_on_drop(true);
+    cmd
+  }
+
+  /// Run the hook and wait for it to finish. A hook that fails is reported,
+  /// and is an error when it's fatal.
+  pub async fn run(&self, opts: &config::Options, name: &str, spec: Option<&Process>, env: &[(&str, String)], rx: &mut mpsc::Receiver<()>) -> Result<()> {
+    tokio::select! {
+      _ = rx.recv() => Err(error::Error::CanceledError),
+      res = self.exec(opts, name, spec, env) => res,
+    }
+  }
+
+  /// Run the hook in the background. The hook is killed when it's aborted.
+  pub fn spawn(&self, opts: &config::Options, name: &str, spec: Option<&Process>, env: &[(&'static str, String)]) -> task::JoinHandle<Result<()>> {
+    let (hook, opts, name, spec, env) = (self.clone(), opts.clone(), name.to_owned(), spec.cloned(), env.to_vec());
+    tokio::spawn(async move {
+      hook.exec(&opts, &name, spec.as_ref(), &env).await
+    })
+  }
+
+  // Run the hook, and report it when it fails.
+  async fn exec(&self, opts: &config::Options, name: &str, spec: Option<&Process>, env: &[(&str, String)]) -> Result<()> {
+    let label = match spec {
+      Some(spec) => format!("{}: {}", spec.key(), name),
+      None => name.to_owned(),
+    };
+    if !opts.quiet() {
+      eprintln!("{}", &format!("----> {}: {}", label, self.run).bold());
+    }
+    let out = match self.command(spec, env).output().await {
+      Ok(out) => out,
+      Err(err) => return self.failed(&label, &format!("could not run: {}", err)),
+    };
+    for line in String::from_utf8_lossy(&out.stdout).lines().chain(String::from_utf8_lossy(&out.stderr).lines()) {
+      match opts.prefix() {
+        true  => println!("{} {}", format!("{} ┃", label).dimmed(), line),
+        false => println!("{}", line),
+      }
+    }
+    if !out.status.success() {
+      return self.failed(&label, &exit::describe(&out.status));
+    }
+    Ok(())
+  }
+
+  // Report a hook that failed.
+  fn failed(&self, label: &str, desc: &str) -> Result<()> {
+    if self.fatal {
+      return Err(error::ExecError::new(&format!("Hook failed: {}: {}", label, desc)).into());
+    }
+    eprintln!("{}", &format!("* * * Hook failed: {}: {}", label, desc).yellow().bold());
+    Ok(())
+  }
+}
+
+impl fmt::Display for Hook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.run)
+  }
+}
+
+impl Serialize for Hook {
+  fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+    match self.fatal {
+      false => serializer.serialize_str(&self.run),
+      true  => HookSpec{run: self.run.clone(), fatal: true}.serialize(serializer),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Hook {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+    struct HookVisitor;
+
+    impl<'de> de::Visitor<'de> for HookVisitor {
+      type Value = Hook;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a command or an object describing a hook")
+      }
+
+      fn visit_str<E: de::Error>(self, v: &str) -> result::Result<Hook, E> {
+        if v.trim().is_empty() {
+          return Err(E::invalid_value(de::Unexpected::Str(v), &self));
+        }
+        Ok(Hook::new(v))
+      }
+
+      fn visit_map<M: de::MapAccess<'de>>(self, m: M) -> result::Result<Hook, M::Error> {
+        let spec = HookSpec::deserialize(de::value::MapAccessDeserializer::new(m))?;
+        let mut hook = self.visit_str::<M::Error>(&spec.run)?;
+        hook.fatal = spec.fatal;
+        Ok(hook)
+      }
+    }
+
+    deserializer.deserialize_any(HookVisitor)
+  }
+}
+
+// A hook is described either by its command or, when it is fatal, an object.
+impl JsonSchema for Hook {
+  fn schema_name() -> std::borrow::Cow<'static, str> {
+    "Hook".into()
+  }
+
+  fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+      "anyOf": [
+        {
+          "type": "string",
+          "minLength": 1,
+          "description": "The command to run with a shell; the hook failing is only reported.",
+        },
+        gen.subschema_for::<HookSpec>(),
+      ],
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_hook() {
+    let hook: Hook = serde_yaml::from_str("mkdir -p run").unwrap();
+    assert!(Hook::new("mkdir -p run") == hook);
+    let hook: Hook = serde_yaml::from_str("{run: rm -f app.sock, fatal: true}").unwrap();
+    assert!(hook.fatal);
+    assert_eq!("run: rm -f app.sock\nfatal: true\n", serde_yaml::to_string(&hook).unwrap());
+    assert!(serde_yaml::from_str::<Hook>("''").is_err());
+
+    let mut spec = Process::new(Some("api"), "./api", vec![], None);
+    spec.env.insert("A".to_string(), "a".to_string());
+    spec.dir = Some("/tmp".to_string());
+    let cmd = hook.command(Some(&spec), &[("PSCTL_EXIT_CODE", "3".to_string())]);
+    let cmd = cmd.as_std();
+    assert_eq!(Some(std::path::Path::new("/tmp")), cmd.get_current_dir());
+    assert_eq!(vec!["-c", "rm -f app.sock"], cmd.get_args().collect::<Vec<_>>());
+    assert!(cmd.get_envs().any(|(k, v)| k == "A" && v == Some("a".as_ref())));
+    assert!(cmd.get_envs().any(|(k, v)| k == "PSCTL_EXIT_CODE" && v == Some("3".as_ref())));
+  }
+
+}
@@ -0,0 +1,234 @@
+use std::io;
+use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use colored::Colorize;
+use nix::unistd::{self, Pid};
+use nix::sys::signal::{self, Signal};
+use tokio::signal::unix;
+use tokio::process;
+use tokio::sync::mpsc;
+
+use crate::config;
+
+use super::error;
+use super::stop_timeout;
+use super::Result;
+
+/// Whether psctl is running as the init process of a container, in which
+/// case it has to act as one.
+pub fn is_pid1() -> bool {
+  unistd::getpid().as_raw() == 1
+}
+
+/// Acts as an init process: every process which is orphaned beneath psctl is
+/// re-parented to it, and is reaped once it exits. The tasks psctl runs are
+/// waited for by the runner, and psctl's other children, which are in its
+/// own process group, by whatever started them; neither is reaped here.
+#[derive(Clone)]
+pub struct Init {
+  tasks: Arc<Mutex<HashSet<i32>>>,
+}
+
+impl Init {
+  /// Start acting as an init process. Unless psctl is PID 1, it becomes a
+  /// subreaper so that orphaned descendants are re-parented to it rather
+  /// than to the system's init.
+  pub fn new() -> Result<Self> {
+    if !is_pid1() {
+      subreaper()?;
+    }
+    let init = Self{
+      tasks: Arc::new(Mutex::new(HashSet::new())),
+    };
+    let mut chld = unix::signal(unix::SignalKind::child())?;
+    let reaper = init.clone();
+    tokio::spawn(async move {
+      loop {
+        tokio::select! {
+          _ = chld.recv() => {},
+          _ = tokio::time::sleep(SWEEP) => {}, // exits that were passed over are collected eventually
+        }
+        reaper.reap();
+      }
+    });
+    Ok(init)
+  }
+
+  /// Spawn a task's process, which is waited for by the runner. It is noted
+  /// before it can be reaped, in case it exits right away.
+  pub fn spawn(&self, cmd: &mut process::Command) -> io::Result<process::Child> {
+    let mut tasks = self.tasks.lock().expect("Init is poisoned");
+    let proc = cmd.spawn()?;
+    if let Some(pid) = proc.id() {
+      tasks.insert(pid as i32);
+    }
+    Ok(proc)
+  }
+
+  // Reap every orphan that has exited. Each child that has exited is reaped
+  // by its process ID, unless it's a task, or one of psctl's own children,
+  // which is left to be waited for by whatever started it.
+  fn reap(&self) {
+    let mut tasks = self.tasks.lock().expect("Init is poisoned");
+    for pid in exited() {
+      if tasks.contains(&pid.as_raw()) || unistd::getpgid(Some(pid)).ok() == Some(unistd::getpgrp()) {
+        continue;
+      }
+      let _ = nix::sys::wait::waitpid(pid, Some(nix::sys::wait::WaitPidFlag::WNOHANG));
+    }
+    tasks.retain(|pid| signal::kill(Pid::from_raw(*pid), None).is_ok()); // forget tasks that have been waited for
+  }
+
+  /// Terminate every descendant that is left once the tasks have been
+  /// stopped, including those that left their task's process group. They
+  /// are asked to stop and, if they don't in time or another request to
+  /// cancel arrives, killed.
+  pub async fn shutdown(&self, opts: &config::Options, rx: &mut mpsc::Receiver<()>) -> Result<()> {
+    self.reap();
+    let procs = descendants();
+    if procs.is_empty() {
+      return Ok(());
+    }
+    if !opts.quiet() {
+      eprintln!("{}", &format!("~~~~> stopping {} remaining processes", procs.len()).bold());
+    }
+    for pid in procs {
+      let _ = signal::kill(pid, Signal::SIGTERM);
+    }
+    let deadline = tokio::time::Instant::now() + stop_timeout();
+    loop {
+      let force = tokio::select! {
+        Some(_) = rx.recv() => true,
+        _ = tokio::time::sleep(SWEEP / 10) => false,
+      };
+      self.reap();
+      let procs = descendants();
+      if procs.is_empty() {
+        return Ok(());
+      }
+      if force || tokio::time::Instant::now() >= deadline {
+        eprintln!("{}", &format!("~~~~> killing {} remaining processes", procs.len()).yellow().bold());
+        for pid in procs {
+          let _ = signal::kill(pid, Signal::SIGKILL);
+        }
+        tokio::time::sleep(SWEEP / 10).await;
+        self.reap();
+        return Ok(());
+      }
+    }
+  }
+}
+
+// How often exited orphans are looked for besides when a child exits.
+const SWEEP: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Become a subreaper, to which orphaned descendants are re-parented.
+#[cfg(target_os = "linux")]
+fn subreaper() -> Result<()> {
+  let res = unsafe { nix::libc::prctl(nix::libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
+  if res != 0 {
+    return Err(error::ExecError::new(&format!("Could not become a subreaper: {}", nix::errno::Errno::last())).into());
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn subreaper() -> Result<()> {
+  Err(error::ExecError::new("Running as an init process is only supported on Linux").into())
+}
+
+// The children of psctl which have exited, and are yet to be reaped.
+fn exited() -> Vec<Pid> {
+  let own = unistd::getpid().as_raw();
+  processes().into_iter().filter(|(_, state, ppid)| *state == 'Z' && *ppid == own).map(|(pid, _, _)| Pid::from_raw(pid)).collect()
+}
+
+// Every process descended from psctl which is still running.
+fn descendants() -> Vec<Pid> {
+  let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+  for (pid, state, ppid) in processes() {
+    if state != 'Z' {
+      children.entry(ppid).or_default().push(pid);
+    }
+  }
+  let mut res: Vec<Pid> = Vec::new();
+  let mut next: Vec<i32> = vec![unistd::getpid().as_raw()];
+  while let Some(pid) = next.pop() {
+    for child in children.remove(&pid).unwrap_or_default() {
+      res.push(Pid::from_raw(child));
+      next.push(child);
+    }
+  }
+  res
+}
+
+// Every process, with its state and parent.
+fn processes() -> Vec<(i32, char, i32)> {
+  let mut res: Vec<(i32, char, i32)> = Vec::new();
+  if let Ok(dir) = fs::read_dir("/proc") {
+    for ent in dir.flatten() {
+      let pid: i32 = match ent.file_name().to_string_lossy().parse() {
+        Ok(pid) => pid,
+        Err(_) => continue,
+      };
+      if let Some((state, ppid)) = fs::read_to_string(ent.path().join("stat")).ok().and_then(|e| parse_stat(&e)) {
+        res.push((pid, state, ppid));
+      }
+    }
+  }
+  res
+}
+
+// The state and parent of a process, from its '/proc/<pid>/stat'. The
+// command name is in parentheses and may contain anything, so fields are
+// read from after the last parenthesis.
+fn parse_stat(stat: &str) -> Option<(char, i32)> {
+  let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+  let state = fields.next()?.chars().next()?;
+  let ppid = fields.next()?.parse().ok()?;
+  Some((state, ppid))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_stat() {
+    assert_eq!(Some(('S', 1)), parse_stat("42 (sh) S 1 42 42 0 -1 4194560"));
+    assert_eq!(Some(('Z', 7)), parse_stat("43 (a) b (c)) Z 7 43 43 0 -1"));
+    assert_eq!(None, parse_stat("44 (truncated"));
+  }
+
+  #[test]
+  fn test_reap() {
+    use std::os::unix::process::CommandExt;
+    subreaper().unwrap();
+    let init = Init{tasks: Arc::new(Mutex::new(HashSet::new()))};
+    let state = |pid: i32| fs::read_to_string(format!("/proc/{}/stat", pid)).ok().and_then(|e| parse_stat(&e)).map(|(state, _)| state);
+    let settle = |pid: i32| for _ in 0..500 {
+      if state(pid) == Some('Z') {
+        break;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+
+    // A task that has exited, but hasn't been waited for yet, doesn't keep
+    // an orphan from being reaped.
+    let mut task = std::process::Command::new("true").process_group(0).spawn().unwrap();
+    init.tasks.lock().unwrap().insert(task.id() as i32);
+    settle(task.id() as i32);
+    let out = std::process::Command::new("sh").args(["-c", "sleep 0.1 & echo $!"]).process_group(0).output().unwrap();
+    let orphan: i32 = String::from_utf8_lossy(&out.stdout).trim().parse().unwrap();
+    settle(orphan);
+    assert_eq!(Some('Z'), state(orphan));
+
+    init.reap();
+    assert_eq!(None, state(orphan));
+    assert_eq!(Some('Z'), state(task.id() as i32));
+    assert!(task.wait().unwrap().success());
+  }
+
+}
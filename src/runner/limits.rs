@@ -0,0 +1,263 @@
+use core::time;
+
+use std::fmt;
+use std::io;
+use std::result;
+
+use nix::sys::resource::{self, Resource};
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+
+use crate::schema;
+
+use super::error;
+use super::Result;
+
+/// Resource limits and the scheduling priority of a task, which apply to it
+/// and everything it runs.
+#[derive(PartialEq, Eq, Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[schemars(deny_unknown_fields)]
+pub struct Limits {
+  /// The maximum number of files the task can have open.
+  #[serde(skip_serializing_if="Option::is_none")]
+  nofile: Option<u64>,
+  /// The largest core dump the task can write, like '512M'; 0 disables core dumps.
+  #[serde(skip_serializing_if="Option::is_none")]
+  core: Option<Bytes>,
+  /// The largest address space the task can have, like '2G'.
+  #[serde(rename = "as", skip_serializing_if="Option::is_none")]
+  address_space: Option<Bytes>,
+  /// How much CPU time the task can use, like '10m'.
+  #[serde(with = "humantime_serde", default, skip_serializing_if="Option::is_none")]
+  #[schemars(schema_with = "schema::duration")]
+  cpu: Option<time::Duration>,
+  /// The maximum number of processes the user the task runs as can have.
+  #[serde(skip_serializing_if="Option::is_none")]
+  nproc: Option<u64>,
+  /// The task's niceness, from -20, the highest priority, to 19, the lowest.
+  #[serde(skip_serializing_if="Option::is_none")]
+  #[schemars(range(min = -20, max = 19))]
+  nice: Option<i32>,
+  /// The task's I/O scheduling class and priority, like 'idle', 'best-effort:7' or 'realtime:0'. Linux only.
+  #[serde(skip_serializing_if="Option::is_none")]
+  ionice: Option<String>,
+}
+
+impl Limits {
+  // The resource limits to set, and their values.
+  fn rlimits(&self) -> Vec<(Resource, &'static str, u64)> {
+    let mut res: Vec<(Resource, &'static str, u64)> = Vec::new();
+    if let Some(n) = self.nofile {
+      res.push((Resource::RLIMIT_NOFILE, "nofile", n));
+    }
+    if let Some(n) = self.core {
+      res.push((Resource::RLIMIT_CORE, "core", n.0));
+    }
+    if let Some(n) = self.address_space {
+      res.push((Resource::RLIMIT_AS, "as", n.0));
+    }
+    if let Some(n) = self.cpu {
+      res.push((Resource::RLIMIT_CPU, "cpu", n.as_secs().max(1)));
+    }
+    if let Some(n) = self.nproc {
+      res.push((Resource::RLIMIT_NPROC, "nproc", n));
+    }
+    res
+  }
+
+  /// Check that the limits can be applied: a limit can't be raised above
+  /// the hard limit psctl has, and priority can't be raised, without the
+  /// privilege to.
+  pub fn validate(&self, key: &str) -> Result<()> {
+    let root = nix::unistd::geteuid().is_root();
+    for (rsrc, name, val) in self.rlimits() {
+      let (_, hard) = resource::getrlimit(rsrc).map_err(io::Error::from)?;
+      if val > hard && !root {
+        return Err(error::ExecError::new(&format!("Limit '{}' of {} exceeds the hard limit of {}, which can only be raised by root: {}", name, val, hard, key)).into());
+      }
+    }
+    if let Some(nice) = self.nice {
+      if !(-20..=19).contains(&nice) {
+        return Err(error::ExecError::new(&format!("Invalid nice value: {}; values range from -20 to 19: {}", nice, key)).into());
+      }
+      if nice < 0 && !root {
+        return Err(error::ExecError::new(&format!("A negative nice value of {} can only be set by root: {}", nice, key)).into());
+      }
+    }
+    if let Some(ionice) = &self.ionice {
+      match parse_ionice(ionice) {
+        Ok(prio) if prio >> 13 == 1 && !root => return Err(error::ExecError::new(&format!("The realtime I/O class can only be set by root: {}", key)).into()),
+        Ok(_) => {},
+        Err(msg) => return Err(error::ExecError::new(&format!("Invalid ionice: {}: {}: {}", ionice, msg, key)).into()),
+      }
+    }
+    Ok(())
+  }
+
+  /// Apply the limits to a command, before it runs.
+  pub fn apply(&self, cmd: &mut std::process::Command) {
+    use std::os::unix::process::CommandExt;
+    let rlimits = self.rlimits();
+    let nice = self.nice;
+    let ionice = self.ionice.as_deref().and_then(|e| parse_ionice(e).ok());
+    let apply = move || -> io::Result<()> {
+      for (rsrc, _, val) in &rlimits {
+        resource::setrlimit(*rsrc, *val, *val)?;
+      }
+      if let Some(nice) = nice {
+        if unsafe { nix::libc::setpriority(nix::libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+          return Err(io::Error::last_os_error());
+        }
+      }
+      if let Some(prio) = ionice {
+        set_ionice(prio)?;
+      }
+      Ok(())
+    };
+    // Only async-signal-safe calls are made between fork and exec.
+    unsafe { cmd.pre_exec(apply); }
+  }
+}
+
+// The I/O priority described by a class and an optional level, as it is
+// provided to 'ioprio_set'.
+fn parse_ionice(text: &str) -> result::Result<i32, String> {
+  let (class, level) = match text.split_once(':') {
+    Some((class, level)) => (class, Some(level)),
+    None => (text, None),
+  };
+  let class = match class {
+    "realtime" => 1,
+    "best-effort" => 2,
+    "idle" => 3,
+    _ => return Err("the class must be one of 'realtime', 'best-effort' or 'idle'".to_string()),
+  };
+  let level = match (class, level) {
+    (3, Some(_)) => return Err("the idle class has no level".to_string()),
+    (_, Some(level)) => match level.parse::<i32>() {
+      Ok(level) if (0..=7).contains(&level) => level,
+      _ => return Err("the level must be from 0, the highest, to 7, the lowest".to_string()),
+    },
+    (_, None) => if class == 3 { 0 } else { 4 },
+  };
+  Ok(class << 13 | level)
+}
+
+#[cfg(target_os = "linux")]
+fn set_ionice(prio: i32) -> io::Result<()> {
+  const IOPRIO_WHO_PROCESS: i32 = 1;
+  if unsafe { nix::libc::syscall(nix::libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ionice(_prio: i32) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "ionice is only supported on Linux"))
+}
+
+/// A number of bytes, described either as a number or with a unit, like
+/// '512K', '64M' or '2G', in powers of 1024.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Bytes(pub u64);
+
+impl Bytes {
+  fn parse(text: &str) -> Option<Self> {
+    let text = text.trim();
+    let (num, mult) = match text.char_indices().last()? {
+      (i, 'K') | (i, 'k') => (&text[..i], 1 << 10),
+      (i, 'M') | (i, 'm') => (&text[..i], 1 << 20),
+      (i, 'G') | (i, 'g') => (&text[..i], 1 << 30),
+      (i, 'T') | (i, 't') => (&text[..i], 1 << 40),
+      _ => (text, 1),
+    };
+    num.trim().parse::<u64>().ok()?.checked_mul(mult).map(Self)
+  }
+}
+
+impl fmt::Display for Bytes {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl Serialize for Bytes {
+  fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+    serializer.serialize_u64(self.0)
+  }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+    struct BytesVisitor;
+
+    impl<'de> de::Visitor<'de> for BytesVisitor {
+      type Value = Bytes;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a number of bytes, like 1048576 or '1M'")
+      }
+
+      fn visit_u64<E: de::Error>(self, v: u64) -> result::Result<Bytes, E> {
+        Ok(Bytes(v))
+      }
+
+      fn visit_i64<E: de::Error>(self, v: i64) -> result::Result<Bytes, E> {
+        match u64::try_from(v) {
+          Ok(v) => Ok(Bytes(v)),
+          Err(_) => Err(E::invalid_value(de::Unexpected::Signed(v), &self)),
+        }
+      }
+
+      fn visit_str<E: de::Error>(self, v: &str) -> result::Result<Bytes, E> {
+        match Bytes::parse(v) {
+          Some(v) => Ok(v),
+          None => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+        }
+      }
+    }
+
+    deserializer.deserialize_any(BytesVisitor)
+  }
+}
+
+// A size is described either by a number of bytes or a string with a unit.
+impl JsonSchema for Bytes {
+  fn schema_name() -> std::borrow::Cow<'static, str> {
+    "Bytes".into()
+  }
+
+  fn json_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+      "description": "A number of bytes, or a number with a unit, like '512K', '64M' or '2G', in powers of 1024.",
+      "anyOf": [
+        {"type": "integer", "minimum": 0},
+        {"type": "string", "pattern": "^[0-9]+ *[KkMmGgTt]?$"},
+      ],
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_limits() {
+    let limits: Limits = serde_yaml::from_str("{nofile: 1024, core: 0, as: 2G, cpu: 10m, nice: 10, ionice: 'best-effort:7'}").unwrap();
+    assert_eq!(Some(Bytes(2 << 30)), limits.address_space);
+    assert_eq!(Some(time::Duration::from_secs(600)), limits.cpu);
+    assert!(limits.validate("api").is_ok());
+
+    assert_eq!(Some(Bytes(512 << 10)), Bytes::parse("512K"));
+    assert_eq!(Some(Bytes(64)), Bytes::parse("64"));
+    assert_eq!(None, Bytes::parse("64X"));
+
+    assert_eq!(Ok(2 << 13 | 7), parse_ionice("best-effort:7"));
+    assert_eq!(Ok(3 << 13), parse_ionice("idle"));
+    assert!(parse_ionice("idle:3").is_err());
+    assert!(parse_ionice("realtime:8").is_err());
+  }
+
+}
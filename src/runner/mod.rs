@@ -1,4 +1,20 @@
 pub mod error;
+mod cgroup;
+mod context;
+mod credentials;
+mod exit;
+mod export;
+mod hooks;
+mod limits;
+mod watch;
+pub mod init;
+pub mod signals;
+
+pub use exit::ExitPolicy;
+pub use hooks::Hook;
+
+/// The status psctl exits with when a task exits before the main task.
+pub const EARLY_EXIT_CODE: i32 = 125;
 
 use core::time;
 
@@ -11,15 +27,20 @@ use std::pin::Pin;
 use std::process::Stdio;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::sync::OnceLock;
 
 use tokio::io::{BufReader, AsyncBufReadExt};
 use tokio::process;
 use tokio::sync::mpsc;
+use tokio::task;
 use futures::stream;
 use futures::stream::TryStreamExt;
 use futures::future::FutureExt;
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use colored::Colorize;
 
 use nix::unistd::Pid;
@@ -27,147 +48,667 @@ use nix::sys::signal;
 use nix::sys::signal::Signal;
 
 use crate::waiter;
+use crate::schema;
 use crate::config;
 use crate::colorwheel;
 
 type Result<T> = result::Result<T, error::Error>;
 
+/// Provides the tasks to run when the pod is reloaded, or a description of
+/// why they could not be read.
+pub type Reload = Box<dyn Fn() -> result::Result<Vec<Process>, String>>;
+
 pub struct Pod {
-  opts:  config::Options,
-  procs: Vec<Process>,
-  wheel: colorwheel::Wheel,
+  opts:   config::Options,
+  procs:  Vec<Process>,
+  wheel:  colorwheel::Wheel,
+  reload: Option<Reload>,
+  policy: ExitPolicy,
+  main:   Option<String>,
+  init:   Option<init::Init>,
+  cgroups: OnceLock<cgroup::Hierarchy>,
+  setup:  Option<Hook>,
+  teardown: Option<Hook>,
 }
 
 impl Pod {
-  pub fn new(opts: config::Options, procs: Vec<Process>) -> Pod {
-    Pod{
+  #[allow(clippy::redundant_field_names)]
+  pub fn new(opts: config::Options, procs: Vec<Process>) -> Result<Pod> {
+    let main = main_task(&procs)?;
+    Ok(Pod{
       opts: opts,
-      procs: procs,
+      procs: replicate(&procs)?,
       wheel: colorwheel::Wheel::default(),
+      reload: None,
+      policy: ExitPolicy::default(),
+      main: main,
+      init: None,
+      cgroups: OnceLock::new(),
+      setup: None,
+      teardown: None,
+    })
+  }
+
+  /// Finish according to the provided exit policy.
+  pub fn with_exit_policy(mut self, policy: ExitPolicy) -> Self {
+    self.policy = policy;
+    self
+  }
+
+  /// Reload tasks on SIGHUP from the provided source.
+  pub fn with_reload(mut self, reload: Reload) -> Self {
+    self.reload = Some(reload);
+    self
+  }
+
+  /// Run until the named task exits. Any other task which exits first, and
+  /// isn't restarted, is a failure.
+  pub fn with_main(mut self, name: &str) -> Self {
+    self.main = Some(name.to_owned());
+    self
+  }
+
+  /// Run hooks before anything is started and after everything has
+  /// stopped.
+  pub fn with_hooks(mut self, setup: Option<Hook>, teardown: Option<Hook>) -> Self {
+    self.setup = setup;
+    self.teardown = teardown;
+    self
+  }
+
+  /// Act as an init process while the pod runs, and terminate every process
+  /// that is left once it finishes.
+  pub fn with_init(mut self, init: init::Init) -> Self {
+    self.init = Some(init);
+    self
+  }
+
+  // The exit policy in effect, which waits for the main task when there is
+  // one.
+  fn exit_policy(&self) -> ExitPolicy {
+    match &self.main {
+      Some(main) => ExitPolicy::WaitFor(vec![main.to_owned()]),
+      None => self.policy.clone(),
     }
   }
 
-  pub async fn exec(&self, rx: &mut mpsc::Receiver<()>) -> Result<i32> {
-    let ord: Vec<&Process> = order_procs(self.procs.iter().map(|e| e).collect())?;
-    let mut tset: Vec<(&Process, process::Command)> = Vec::new();
-    let mut pset: Vec<(&Process, process::Child)> = Vec::new();
-    for spec in &ord {
-      tset.push((spec, spec.task()?));
+  // The cgroup hierarchy tasks run in, which is set up when the first task
+  // that needs it is run.
+  fn cgroups(&self) -> Result<&cgroup::Hierarchy> {
+    if let Some(cgroups) = self.cgroups.get() {
+      return Ok(cgroups);
+    }
+    let _ = self.cgroups.set(cgroup::Hierarchy::new()?);
+    Ok(self.cgroups.get().expect("Cgroups are set"))
+  }
+
+  // Describe how a task exited, which includes being killed by the OOM
+  // killer when it ran out of memory in its cgroup.
+  fn describe(&self, key: &str, stat: &std::process::ExitStatus) -> String {
+    match self.cgroups.get() {
+      Some(cgroups) if stat.signal() == Some(Signal::SIGKILL as i32) && cgroups.oom_killed(key) => format!("killed by the OOM killer ({})", exit::code(stat)),
+      _ => exit::describe(stat),
+    }
+  }
+
+  /// Run the pod until it finishes or is canceled. The signals that are
+  /// forwarded to tasks are received from the time 'forward' was set up, so
+  /// one that arrives while tasks are starting is forwarded once they're up.
+  pub async fn exec(&self, forward: &mut signals::Forward, rx: &mut mpsc::Receiver<()>) -> Result<i32> {
+    let ord: Vec<Process> = order_procs(self.procs.iter().collect())?.into_iter().cloned().collect();
+    let mut pset: Vec<(Process, process::Child)> = Vec::new();
+    let mut exited: Vec<(String, std::process::ExitStatus)> = Vec::new();
+    check_procs(&ord.iter().collect::<Vec<&Process>>())?;
+    if let Some(main) = &self.main {
+      if !ord.iter().any(|e| exit::names_task(main, e.key())) {
+        return Err(error::ExecError::new(&format!("Main task is not defined: {}", main)).into());
+      }
+    }
+    self.exit_policy().validate(&ord.iter().collect::<Vec<&Process>>())?;
+    if ord.iter().any(|e| e.cgroup.is_some()) {
+      self.cgroups()?;
+    }
+    if let Some(setup) = &self.setup {
+      setup.run(&self.opts, "setup", None, &[], rx).await?;
     }
 
     // run processes
-    let res = self._exec(&ord, &mut tset, &mut pset, rx).await;
-    // explicitly clean up after processes
-    Self::cleanup(&self.opts, &mut pset).await?;
+    let mut ctx = context::Context::default();
+    let mut hooks: Vec<task::JoinHandle<Result<()>>> = Vec::new();
+    let res = self._exec(ord, &mut ctx, &mut pset, &mut exited, &mut hooks, forward, rx).await;
+    // explicitly clean up after processes; everything that follows is done
+    // regardless of what fails, and the first error is returned
+    let mut errs: Vec<error::Error> = Vec::new();
+    errs.extend(Self::cleanup(&self.opts, self.cgroups.get(), &mut pset, rx).await.err());
+    errs.extend(Self::finish_hooks(hooks, rx).await.err());
+    if !errs.iter().any(|e| matches!(e, error::Error::CanceledError)) {
+      for (spec, _) in pset.iter().filter(|(spec, _)| !exited.iter().any(|(key, _)| key == spec.key())) {
+        if let Err(err) = self.after_exit(spec, None, &ctx, rx).await { // tasks that exited on their own have run theirs
+          errs.push(err);
+          break;
+        }
+      }
+    }
+    if let Some(init) = &self.init {
+      errs.extend(init.shutdown(&self.opts, rx).await.err());
+    }
+    if let Some(teardown) = &self.teardown {
+      errs.extend(teardown.run(&self.opts, "teardown", None, &[], rx).await.err());
+    }
+    // describe how everything finished
+    self.summary(&pset, &exited);
+    if let Some(cgroups) = self.cgroups.get() {
+      for (spec, _) in pset.iter().filter(|(spec, _)| spec.cgroup.is_some()) {
+        cgroups.remove(spec.key()).await;
+      }
+      cgroups.close();
+    }
     // return the result
+    match (res, errs.into_iter().next()) {
+      (Ok(_), Some(err)) => Err(err),
+      (res, _) => res,
+    }
+  }
+
+  // Wait for the 'after_exit' hooks that run in the background to finish.
+  // They're all killed when psctl is interrupted.
+  async fn finish_hooks(mut hooks: Vec<task::JoinHandle<Result<()>>>, rx: &mut mpsc::Receiver<()>) -> Result<()> {
+    let mut res: Result<()> = Ok(());
+    for i in 0..hooks.len() {
+      let done = tokio::select! {
+        _ = rx.recv() => None,
+        done = &mut hooks[i] => Some(done),
+      };
+      let done = match done {
+        Some(done) => done,
+        None => {
+          hooks[i..].iter().for_each(|e| e.abort());
+          return Err(error::Error::CanceledError);
+        },
+      };
+      if let Err(err) = done.map_err(io::Error::from).map_err(error::Error::from).and_then(|e| e) {
+        if res.is_ok() {
+          res = Err(err);
+        }
+      }
+    }
     res
   }
 
-  pub async fn _exec<'a>(&self, ord: &Vec<&Process>, tset: &mut Vec<(&'a Process, process::Command)>, pset: &mut Vec<(&'a Process, process::Child)>, rx: &mut mpsc::Receiver<()>) -> Result<i32> {
+  // Run tasks in order, then watch over them. Every task that is started is
+  // kept in the process set, in the order it was started, so that it can be
+  // cleaned up no matter how this ends; tasks which have exited for good are
+  // recorded, in the order they exited, along with their status.
+  #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+  pub async fn _exec(&self, ord: Vec<Process>, ctx: &mut context::Context, pset: &mut Vec<(Process, process::Child)>, exited: &mut Vec<(String, std::process::ExitStatus)>, hooks: &mut Vec<task::JoinHandle<Result<()>>>, forward: &mut signals::Forward, rx: &mut mpsc::Receiver<()>) -> Result<i32> {
     if !self.opts.quiet() {
       eprintln!("{}", &format!("====> {}", ord.iter().map(|e| e.key()).collect::<Vec<&str>>().join(", ")).bold());
     }
-    let maxkey: usize = min(32, tset.iter().map(|(spec, _)| spec.key().len()).max().unwrap_or(0));
+    let policy = self.exit_policy();
+    let mut colors = Colors::default();
+    let mut maxkey: usize = max_key(&ord);
 
-    let mut i: usize = 0;
-    for (spec, task) in tset {
-      let mut proc = match task.spawn() {
-        Ok(proc) => proc,
-        Err(err) => return Err(error::ExecError::new(&format!("Could not run process: {}; because: {}", spec, err)).into()),
-      };
+    for spec in ord {
+      let (proc, res) = self.start(colors.get(spec.key()), maxkey, &spec, ctx, rx).await?;
+      pset.push((spec, proc));
+      res?;
+    }
 
-      let mut stdout = match proc.stdout.take() {
-        Some(stdout) => BufReader::new(stdout).lines(),
-        None         => return Err(error::ExecError::new(&format!("Could not configure process STDOUT: {}", spec)).into()),
-      };
-      let mut stderr = match proc.stderr.take() {
-        Some(stderr) => BufReader::new(stderr).lines(),
-        None         => return Err(error::ExecError::new(&format!("Could not configure process STDERR: {}", spec)).into()),
-      };
+    let (mut changes, mut _watchers) = self.watch(pset)?;
+    let mut pending: HashMap<usize, tokio::time::Instant> = HashMap::new();
 
-      let key_stdout = match self.opts.prefix() {
-        true  => Some(self.wheel.colorize(i, format!("{} ┃", spec.key_with_padding(maxkey)))),
-        false => None,
-      };
-      tokio::spawn(async move {
-        while let Some(line) = stdout.next_line().await.expect("Could not read from STDOUT") {
-          if let Some(pfx) = &key_stdout {
-            println!("{} {}", pfx, line);
+    let code = loop {
+      let settle = pending.values().min().copied();
+      let event = {
+        let mut jobs: Vec<Pin<Box<dyn futures::Future<Output = Result<(usize, std::process::ExitStatus)>> + '_>>> = Vec::new();
+        for (i, (spec, proc)) in pset.iter_mut().enumerate() {
+          if !exited.iter().any(|(key, _)| key == spec.key()) {
+            jobs.push(Box::pin(proc.wait().map(move |f| Ok((i, f?)))));
           }
         }
-      });
 
-      let key_stderr = match self.opts.prefix() {
-        true  => Some(self.wheel.colorize(i, format!("{} ┃", spec.key_with_padding(maxkey)))),
-        false => None,
+        let mut jobs = stream::FuturesUnordered::from_iter(jobs);
+        tokio::select! {
+          _ = rx.recv() =>  return Err(error::Error::CanceledError),
+          res = jobs.try_next() => match res? {
+            Some((i, stat)) => Event::Exit(i, stat),
+            None => break policy.code(exited), // nothing is running
+          },
+          Some(i) = changes.recv() => Event::Change(i),
+          _ = tokio::time::sleep_until(settle.unwrap_or_else(tokio::time::Instant::now)), if settle.is_some() => Event::Settled,
+          sig = forward.recv() => Event::Signal(sig),
+        }
       };
-      tokio::spawn(async move {
-        while let Some(line) = stderr.next_line().await.expect("Could not read from STDERR") {
-          if let Some(pfx) = &key_stderr {
-            println!("{} {}", pfx, line);
+
+      match event {
+        Event::Exit(i, stat) => {
+          let spec = &pset[i].0;
+          if !spec.restart.unwrap_or_default().applies(&stat) {
+            hooks.extend(self.spawn_after_exit(spec, &stat, ctx));
+            exited.push((spec.key().to_owned(), stat));
+            if let Some(main) = &self.main {
+              if !exit::names_task(main, spec.key()) && !stat.success() && spec.critical != Some(false) {
+                return Err(error::Error::EarlyExitError(spec.key().to_owned(), main.to_owned(), self.describe(spec.key(), &stat)));
+              }
+            }
+            if policy.critical(spec) {
+              break exit::code(&stat);
+            }
+            if policy.finished(&pset.iter().map(|(spec, _)| spec).collect::<Vec<&Process>>(), exited) {
+              break policy.code(exited);
+            }
+            if !self.opts.quiet() {
+              eprintln!("{}", &format!("----> {}: {}", spec.key(), self.describe(spec.key(), &stat)).bold());
+            }
+            continue;
           }
+          if !self.opts.quiet() {
+            eprintln!("{}", &format!("----> {}: {}; restarting", spec.key(), self.describe(spec.key(), &stat)).bold());
+          }
+          self.after_exit(spec, Some(&stat), ctx, rx).await?; // the task is only restarted once it has finished
+          tokio::select! {
+            _ = rx.recv() => return Err(error::Error::CanceledError),
+            _ = tokio::time::sleep(restart_delay()) => {},
+          }
+          let (proc, res) = self.start(colors.get(spec.key()), maxkey, spec, ctx, rx).await?;
+          pset[i].1 = proc;
+          res?;
+        },
+        Event::Change(i) => if let Some(conf) = &pset[i].0.watch {
+          pending.insert(i, tokio::time::Instant::now() + conf.debounce());
+        },
+        Event::Settled => {
+          let now = tokio::time::Instant::now();
+          let ready: Vec<usize> = pending.iter().filter(|(_, at)| **at <= now).map(|(i, _)| *i).collect();
+          for i in &ready {
+            pending.remove(i);
+          }
+          let specs: Vec<&Process> = pset.iter().map(|(spec, _)| spec).collect();
+          let restart = affected(&specs, &ready);
+          if !self.opts.quiet() {
+            eprintln!("{}", &format!("----> {}: files changed; restarting {}", ready.iter().map(|i| specs[*i].key()).collect::<Vec<&str>>().join(", "), restart.iter().map(|i| specs[*i].key()).collect::<Vec<&str>>().join(", ")).bold());
+          }
+          for i in restart.iter().rev() { // stop dependents before their dependencies
+            let (spec, proc) = &mut pset[*i];
+            let running = !exited.iter().any(|(key, _)| key == spec.key());
+            Self::stop(&self.opts, self.cgroups.get(), spec, proc).await?;
+            if running {
+              self.after_exit(spec, None, ctx, rx).await?;
+            }
+          }
+          // A task that doesn't become available again is reported rather than
+          // ending the pod, and the tasks that depend on it are left stopped;
+          // they're all restarted when their files change again.
+          let mut failed: Vec<String> = Vec::new();
+          for i in &restart {
+            let (spec, proc) = &mut pset[*i];
+            if let Some(dep) = spec.deps().iter().find(|e| failed.contains(e)) {
+              eprintln!("{}", &format!("* * * {}: not restarted, since {} is not available", spec.key(), dep).yellow().bold());
+              if !exited.iter().any(|(key, _)| key == spec.key()) {
+                if let Some(stat) = proc.try_wait()? {
+                  exited.push((spec.key().to_owned(), stat));
+                }
+              }
+              failed.push(spec.key().to_owned());
+              continue;
+            }
+            let (next, res) = self.start(colors.get(spec.key()), maxkey, spec, ctx, rx).await?;
+            exited.retain(|(key, _)| key != spec.key());
+            *proc = next;
+            match res {
+              Ok(()) => {},
+              Err(error::Error::CanceledError) => return Err(error::Error::CanceledError),
+              Err(err) => {
+                eprintln!("{}", &format!("* * * {}: not available after restarting: {}", spec.key(), err).yellow().bold());
+                failed.push(spec.key().to_owned());
+              },
+            }
+          }
+        },
+        Event::Signal(sig) => {
+          let specs: Vec<&Process> = pset.iter().map(|(spec, _)| spec).filter(|spec| !exited.iter().any(|(key, _)| key == spec.key())).collect();
+          let mut sent: Vec<&str> = Vec::new();
+          for spec in &specs {
+            if !signals::receives(spec, sig, &specs) {
+              continue;
+            }
+            if let Some(pid) = pset.iter().find(|(e, _)| e.key() == spec.key()).and_then(|(_, proc)| proc.id()) {
+              if signal::kill(Pid::from_raw(-(pid as i32)), sig).is_ok() { // negative-pid addresses the process group
+                sent.push(spec.key());
+              }
+            }
+          }
+          if self.opts.verbose() && !sent.is_empty() {
+            eprintln!("{}", &format!("----> forwarded {} to {}", sig, sent.join(", ")).italic());
+          }
+          if sig != Signal::SIGHUP {
+            continue;
+          }
+          if self.reload.is_none() {
+            if sent.is_empty() { // nothing handles a hangup, so it stops the pod like it would by default
+              return Err(error::Error::CanceledError);
+            }
+            continue;
+          }
+          let ord = match self.reloaded() {
+            Ok(ord) => ord,
+            Err(err) => {
+              eprintln!("{}", &format!("* * * Could not reload: {}; tasks are unchanged", err).yellow().bold());
+              continue;
+            },
+          };
+          maxkey = max_key(&ord);
+          let diff = self.apply(ord, &mut colors, maxkey, ctx, pset, rx).await?;
+          exited.retain(|(key, _)| !diff.added.contains(key) && !diff.removed.contains(key) && !diff.restart.contains(key));
+          (changes, _watchers) = self.watch(pset)?; // tasks may have moved
+          pending.clear();
+        },
+      }
+    };
+
+    if !self.opts.quiet() {
+      eprintln!("{}", "====> finished".bold());
+    }
+    Ok(code)
+  }
+
+  // Watch the files of tasks which restart when they change.
+  fn watch(&self, pset: &[(Process, process::Child)]) -> Result<(mpsc::UnboundedReceiver<usize>, Vec<notify::RecommendedWatcher>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let watchers = watch::watch(&pset.iter().map(|(spec, _)| spec).collect::<Vec<&Process>>(), tx)?;
+    Ok((rx, watchers))
+  }
+
+  // Read the tasks to run again, in order, and check that they can be run.
+  fn reloaded(&self) -> result::Result<Vec<Process>, String> {
+    let procs = match &self.reload {
+      Some(reload) => reload()?,
+      None => return Err("Reloading is not supported".to_string()),
+    };
+    let procs = replicate(&procs).map_err(|e| e.to_string())?;
+    let ord: Vec<Process> = order_procs(procs.iter().collect()).map_err(|e| e.to_string())?.into_iter().cloned().collect();
+    check_procs(&ord.iter().collect::<Vec<&Process>>()).map_err(|e| e.to_string())?;
+    self.exit_policy().validate(&ord.iter().collect::<Vec<&Process>>()).map_err(|e| e.to_string())?;
+    if ord.iter().any(|e| e.cgroup.is_some()) {
+      self.cgroups().map_err(|e| e.to_string())?;
+    }
+    Ok(ord)
+  }
+
+  // Apply a new set of tasks to those that are running. Tasks which were
+  // removed are stopped, tasks which were added are started, and tasks which
+  // changed are restarted along with the tasks that depend on them. Tasks
+  // which are unaffected are left running.
+  async fn apply(&self, ord: Vec<Process>, colors: &mut Colors, maxkey: usize, ctx: &mut context::Context, pset: &mut Vec<(Process, process::Child)>, rx: &mut mpsc::Receiver<()>) -> Result<Diff> {
+    let diff = Diff::new(pset, &ord);
+    if !self.opts.quiet() {
+      eprintln!("{}", &format!("====> reloaded: {}", diff).bold());
+    }
+    for (spec, proc) in pset.iter_mut().rev() { // stop dependents before their dependencies
+      if diff.removed.iter().chain(&diff.restart).any(|e| e == spec.key()) {
+        let running = proc.id().is_some();
+        Self::stop(&self.opts, self.cgroups.get(), spec, proc).await?;
+        if running {
+          self.after_exit(spec, None, ctx, rx).await?;
         }
-      });
+        ctx.forget(spec);
+      }
+      if diff.removed.iter().any(|e| e == spec.key()) && spec.cgroup.is_some() {
+        self.cgroups()?.remove(spec.key()).await;
+      }
+    }
 
-      if !self.opts.quiet() {
-        eprintln!("{}", &format!("----> {}", spec).bold());
+    let mut prev: HashMap<String, (Process, process::Child)> = pset.drain(..).map(|e| (e.0.key().to_owned(), e)).collect();
+    for spec in ord {
+      let key = spec.key().to_owned();
+      if diff.restart.contains(&key) || diff.added.contains(&key) {
+        let res = match self.start(colors.get(&key), maxkey, &spec, ctx, rx).await {
+          Ok((proc, res)) => {
+            pset.push((spec, proc));
+            res
+          },
+          Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+          pset.extend(prev.into_values()); // make sure everything is cleaned up
+          return Err(err);
+        }
+      }else if let Some(e) = prev.remove(&key) {
+        pset.push(e);
       }
-      let checks = spec.checks();
-      let res = if !checks.is_empty(){
-        let waitconf = waiter::Config::from_options(spec.key().to_owned(), &self.opts);
-        tokio::select! {
-          _   = rx.recv()   => Err(error::Error::CanceledError),
-          _   = proc.wait() => Err(error::Error::NeverInitializedError(spec.key().to_owned())),
-          res = waiter::wait_config(&waitconf, checks, spec.wait) =>  match res {
-            Ok(_)    => Ok((spec.key(), false)),
-            Err(err) => Err(err.into()),
-          }
+    }
+    Ok(diff)
+  }
+
+  // Start a task and wait for it to become available. The process is returned
+  // along with the result of waiting, so that it can be cleaned up even when
+  // it doesn't become available.
+  async fn start(&self, i: usize, maxkey: usize, spec: &Process, ctx: &mut context::Context, rx: &mut mpsc::Receiver<()>) -> Result<(process::Child, Result<()>)> {
+    ctx.allocate(spec)?;
+    let task = ctx.resolve(spec)?;
+    let group = match &task.cgroup {
+      Some(conf) => Some(self.cgroups()?.create(task.key(), conf).await?),
+      None => None,
+    };
+    let mut cmd: process::Command = task.task_in(group)?;
+    if let Some(hook) = &task.before {
+      hook.run(&self.opts, "before", Some(&task), &[], rx).await?;
+    }
+    for check in task.checks() {
+      check.prepare()?;
+    }
+    let started = std::time::SystemTime::now();
+    let (mut proc, capture) = self.spawn(i, maxkey, &task, &mut cmd)?;
+    let res = match self.available(&task, &mut proc, &capture, started, rx).await {
+      Ok(values) => {
+        ctx.export(spec, values);
+        match &task.after_available {
+          Some(hook) => hook.run(&self.opts, "after_available", Some(&task), &[], rx).await,
+          None => Ok(()),
         }
-      } else {
-        Ok((spec.key(), true)) // immediately available if we have no checks
-      };
-      pset.push((spec, proc));
-      match res {
-        Ok((key, dflt))  => if (!dflt && !self.opts.quiet()) || self.opts.verbose() {
-          eprintln!("{}", &format!("----> {}: available", key).bold());
+      },
+      Err(err) => Err(err),
+    };
+    Ok((proc, res))
+  }
+
+  // Run a task's hook once it has exited, with the status it exited with
+  // when it exited on its own rather than being stopped.
+  async fn after_exit(&self, spec: &Process, stat: Option<&std::process::ExitStatus>, ctx: &context::Context, rx: &mut mpsc::Receiver<()>) -> Result<()> {
+    let hook = match &spec.after_exit {
+      Some(hook) => hook,
+      None => return Ok(()),
+    };
+    let task = ctx.resolve(spec).unwrap_or_else(|_| spec.clone());
+    let env: Vec<(&str, String)> = match stat {
+      Some(stat) => vec![("PSCTL_EXIT_CODE", exit::code(stat).to_string())],
+      None => Vec::new(),
+    };
+    hook.run(&self.opts, "after_exit", Some(&task), &env, rx).await
+  }
+
+  // Run the 'after_exit' hook of a task which has exited for good in the
+  // background, so that the other tasks go on being watched over meanwhile.
+  fn spawn_after_exit(&self, spec: &Process, stat: &std::process::ExitStatus, ctx: &context::Context) -> Option<task::JoinHandle<Result<()>>> {
+    let hook = spec.after_exit.as_ref()?;
+    let task = ctx.resolve(spec).unwrap_or_else(|_| spec.clone());
+    Some(hook.spawn(&self.opts, "after_exit", Some(&task), &[("PSCTL_EXIT_CODE", exit::code(stat).to_string())]))
+  }
+
+  // Stop a running task with its stop signal. A task that doesn't exit in
+  // time is killed. When the task has a cgroup, whatever is left in it once
+  // the task has exited is killed too.
+  async fn stop(opts: &config::Options, cgroups: Option<&cgroup::Hierarchy>, spec: &Process, proc: &mut process::Child) -> Result<()> {
+    let cgroup = cgroups.filter(|_| spec.cgroup.is_some());
+    let pid = match proc.id() {
+      Some(pid) => Pid::from_raw(-(pid as i32)), // negative-pid addresses the process group
+      None => return Ok(()), // already exited
+    };
+    if signal::kill(pid, spec.stop_signal()?).is_ok() {
+      tokio::select! {
+        res = proc.wait() => { res?; },
+        _ = tokio::time::sleep(stop_timeout()) => {
+          if !opts.quiet() {
+            eprintln!("{}", &format!("~~~~> {}: did not stop after {}; killing", spec.key(), humantime::format_duration(stop_timeout())).bold());
+          }
+          let _ = signal::kill(pid, Signal::SIGKILL);
+          proc.wait().await?;
         },
-        Err(err) => return Err(err),
       }
-
-      i = i + 1;
     }
+    if let Some(cgroups) = cgroup {
+      cgroups.kill(spec.key());
+    }
+    Ok(())
+  }
+
+  fn spawn(&self, i: usize, maxkey: usize, spec: &Process, task: &mut process::Command) -> Result<(process::Child, export::Capture)> {
+    let res = match &self.init {
+      Some(init) => init.spawn(task),
+      None => task.spawn(),
+    };
+    let mut proc = match res {
+      Ok(proc) => proc,
+      Err(err) => return Err(error::ExecError::new(&format!("Could not run process: {}; because: {}", spec, err)).into()),
+    };
+
+    let mut stdout = match proc.stdout.take() {
+      Some(stdout) => BufReader::new(stdout).lines(),
+      None         => return Err(error::ExecError::new(&format!("Could not configure process STDOUT: {}", spec)).into()),
+    };
+    let mut stderr = match proc.stderr.take() {
+      Some(stderr) => BufReader::new(stderr).lines(),
+      None         => return Err(error::ExecError::new(&format!("Could not configure process STDERR: {}", spec)).into()),
+    };
+
+    let capture = export::Capture::new(&spec.exports)?;
 
-    let code = {
-      let mut jobs: Vec<Pin<Box<dyn futures::Future<Output = Result<i32>>>>> = Vec::new();
-      for (_, proc) in pset {
-        jobs.push(Box::pin(proc.wait().map(|f| match f?.code() {
-          Some(code) => Ok(code),
-          None => Ok(0),
-        })));
+    let key_stdout = match self.opts.prefix() {
+      true  => Some(self.wheel.colorize(i, format!("{} ┃", spec.key_with_padding(maxkey)))),
+      false => None,
+    };
+    let capture_stdout = capture.clone();
+    tokio::spawn(async move {
+      while let Some(line) = stdout.next_line().await.expect("Could not read from STDOUT") {
+        capture_stdout.scan(&line);
+        if let Some(pfx) = &key_stdout {
+          println!("{} {}", pfx, line);
+        }
+      }
+    });
+
+    let key_stderr = match self.opts.prefix() {
+      true  => Some(self.wheel.colorize(i, format!("{} ┃", spec.key_with_padding(maxkey)))),
+      false => None,
+    };
+    let capture_stderr = capture.clone();
+    tokio::spawn(async move {
+      while let Some(line) = stderr.next_line().await.expect("Could not read from STDERR") {
+        capture_stderr.scan(&line);
+        if let Some(pfx) = &key_stderr {
+          println!("{} {}", pfx, line);
+        }
       }
+    });
+
+    if !self.opts.quiet() {
+      eprintln!("{}", &format!("----> {}", spec).bold());
+    }
+    Ok((proc, capture))
+  }
 
-      let mut jobs = stream::FuturesUnordered::from_iter(jobs);
+  // Wait for a task to become available, which is once its checks pass and
+  // the values it exports can be collected. The exported values are returned.
+  async fn available(&self, spec: &Process, proc: &mut process::Child, capture: &export::Capture, started: std::time::SystemTime, rx: &mut mpsc::Receiver<()>) -> Result<HashMap<String, String>> {
+    let checks = spec.checks();
+    let res = if !checks.is_empty(){
+      let waitconf = waiter::Config::from_options(spec.key().to_owned(), &self.opts).with_started(started).with_pid(proc.id());
       tokio::select! {
-        _ = rx.recv() =>  return Err(error::Error::CanceledError),
-        res = jobs.try_next() => (res?).unwrap_or_default(),
+        _   = rx.recv()   => Err(error::Error::CanceledError),
+        _   = proc.wait() => Err(error::Error::NeverInitializedError(spec.key().to_owned())),
+        res = waiter::wait_config(&waitconf, checks, spec.wait) =>  match res {
+          Ok(_)    => Ok((spec.key(), false)),
+          Err(err) => Err(err.into()),
+        }
       }
+    } else {
+      Ok((spec.key(), true)) // immediately available if we have no checks
     };
+    let (key, dflt) = res?;
 
-    if !self.opts.quiet() {
-      eprintln!("{}", "====> finished".bold());
+    let values = if !spec.exports().is_empty() {
+      tokio::select! {
+        _   = rx.recv()   => Err(error::Error::CanceledError),
+        _   = proc.wait() => Err(error::Error::NeverInitializedError(spec.key().to_owned())),
+        res = export::wait(spec, capture, started) => res,
+      }?
+    } else {
+      HashMap::new()
+    };
+
+    if (!dflt && !self.opts.quiet()) || self.opts.verbose() {
+      eprintln!("{}", &format!("----> {}: available", key).bold());
     }
-    Ok(code)
+    if self.opts.verbose() && !values.is_empty() {
+      let mut names: Vec<&str> = values.keys().map(|e| e.as_str()).collect();
+      names.sort();
+      eprintln!("{}", &format!("----> {}: exported {}", key, names.join(", ")).italic());
+    }
+    Ok(values)
   }
 
-  async fn cleanup(opts: &config::Options, pset: &mut Vec<(&Process, process::Child)>) -> Result<()> {
-    // explicitly clean up after remaining processes
-    for (spec, proc) in pset {
+  // Describe how every task finished: with the status it exited with, or
+  // stopped when the pod finished.
+  fn summary(&self, pset: &[(Process, process::Child)], exited: &[(String, std::process::ExitStatus)]) {
+    if self.opts.quiet() || pset.is_empty() {
+      return;
+    }
+    let maxkey = min(32, pset.iter().map(|(spec, _)| spec.key().len()).max().unwrap_or(0));
+    eprintln!("{}", "====> summary".bold());
+    for (spec, _) in pset {
+      let desc = match exited.iter().find(|(key, _)| key == spec.key()) {
+        Some((_, stat)) => self.describe(spec.key(), stat),
+        None => "stopped".to_string(),
+      };
+      eprintln!("      {} {}", spec.key_with_padding(maxkey), desc);
+    }
+  }
+
+  // Stop every task that is still running with its stop signal. When another
+  // request to cancel arrives while this is underway, every task that is left
+  // is killed immediately instead. Whatever is left in the cgroups of tasks
+  // that have them is killed once they have stopped.
+  async fn cleanup(opts: &config::Options, cgroups: Option<&cgroup::Hierarchy>, pset: &mut [(Process, process::Child)], rx: &mut mpsc::Receiver<()>) -> Result<()> {
+    let mut force = false;
+    for i in 0..pset.len() {
+      let (spec, proc) = &mut pset[i];
       if let Some(pid) = proc.id() { // negative-pid addresses the process group
-        if let Err(err) = signal::kill(Pid::from_raw(-(pid as i32)), Signal::SIGTERM) {
+        let sig = match force {
+          true  => Signal::SIGKILL,
+          false => spec.stop_signal()?,
+        };
+        if let Err(err) = signal::kill(Pid::from_raw(-(pid as i32)), sig) {
           eprintln!("{}", &format!("~~~~> {} [failed] {}", spec, err).bold());
           continue; // could not kill this one, it has possibly already exited; move on
         }
-        match proc.wait().await {
+        let res = tokio::select! {
+          res = proc.wait() => res,
+          Some(_) = rx.recv(), if !force => {
+            force = true;
+            eprintln!("{}", "~~~~> killing every task".yellow().bold());
+            for (spec, proc) in pset[i..].iter() {
+              if let Some(pid) = proc.id() {
+                let _ = signal::kill(Pid::from_raw(-(pid as i32)), Signal::SIGKILL);
+              }
+              if let Some(cgroups) = cgroups.filter(|_| spec.cgroup.is_some()) {
+                cgroups.kill(spec.key());
+              }
+            }
+            pset[i].1.wait().await
+          },
+        };
+        let spec = &pset[i].0;
+        match res {
           Ok(_) => {
             if !opts.quiet() {
               eprintln!("{}", &format!("~~~~> {} [{} killed]", spec, pid).bold());
@@ -184,68 +725,326 @@ impl Pod {
         };
       }
     }
+    if let Some(cgroups) = cgroups {
+      for (spec, _) in pset.iter().filter(|(spec, _)| spec.cgroup.is_some()) {
+        cgroups.kill(spec.key());
+      }
+    }
     Ok(())
   }
 }
 
+// Something that happens while tasks are running.
+enum Event {
+  Exit(usize, std::process::ExitStatus),
+  Change(usize),
+  Settled,
+  Signal(Signal),
+}
+
+// The width of task keys when prefixing output.
+fn max_key(ord: &[Process]) -> usize {
+  min(32, ord.iter().map(|spec| spec.key().len()).max().unwrap_or(0))
+}
+
+// Assigns each task a color the first time it is seen, so that a task keeps
+// its color when tasks are added or removed around it.
+#[derive(Default)]
+struct Colors(HashMap<String, usize>);
+
+impl Colors {
+  fn get(&mut self, key: &str) -> usize {
+    let next = self.0.len();
+    *self.0.entry(key.to_owned()).or_insert(next)
+  }
+}
+
+// The difference between the tasks that are running and those that should
+// be. Tasks that changed are restarted along with the tasks that depend on
+// them, directly or not.
+#[derive(Debug, Default, PartialEq)]
+struct Diff {
+  added: Vec<String>,
+  removed: Vec<String>,
+  restart: Vec<String>,
+}
+
+impl Diff {
+  fn new(pset: &[(Process, process::Child)], ord: &[Process]) -> Self {
+    Self::from_specs(&pset.iter().map(|(spec, _)| spec).collect::<Vec<&Process>>(), ord)
+  }
+
+  fn from_specs(prev: &[&Process], ord: &[Process]) -> Self {
+    let prev: HashMap<&str, &Process> = prev.iter().map(|e| (e.key(), *e)).collect();
+    let next: HashSet<&str> = ord.iter().map(|e| e.key()).collect();
+    let mut diff = Self::default();
+    for key in prev.keys() {
+      if !next.contains(key) {
+        diff.removed.push(key.to_string());
+      }
+    }
+    diff.removed.sort();
+    for spec in ord { // tasks are ordered, so dependencies are considered first
+      match prev.get(spec.key()) {
+        None => diff.added.push(spec.key().to_owned()),
+        Some(cur) => if *cur != spec || spec.deps().iter().any(|e| diff.restart.contains(e)) {
+          diff.restart.push(spec.key().to_owned());
+        },
+      }
+    }
+    diff
+  }
+}
+
+impl fmt::Display for Diff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let list = |e: &Vec<String>| match e.is_empty() {
+      true  => "none".to_string(),
+      false => e.join(", "),
+    };
+    write!(f, "added {}; removed {}; restarted {}", list(&self.added), list(&self.removed), list(&self.restart))
+  }
+}
+
+// The tasks to restart when files change: the tasks that watch them, and the
+// tasks that depend on those, directly or not, when they should be restarted
+// too. Tasks are provided in the order they are started.
+fn affected(ord: &[&Process], changed: &[usize]) -> Vec<usize> {
+  let mut keys: HashSet<&str> = HashSet::new();
+  let mut res: Vec<usize> = Vec::new();
+  for (i, spec) in ord.iter().enumerate() {
+    let dependent = spec.deps().iter().any(|e| keys.contains(e.as_str()));
+    if changed.contains(&i) || dependent {
+      res.push(i);
+      if dependent || spec.watch.as_ref().map(|e| e.dependents()).unwrap_or_default() {
+        keys.insert(spec.key());
+      }
+    }
+  }
+  res
+}
+
 fn wait_default() -> time::Duration {
-  return time::Duration::from_secs(30)
+  time::Duration::from_secs(30)
+}
+
+fn stop_timeout() -> time::Duration {
+  time::Duration::from_secs(10)
+}
+
+// Parse the name of a signal, with or without its 'SIG' prefix.
+fn parse_signal(name: &str) -> Option<Signal> {
+  let name = name.trim().to_ascii_uppercase();
+  match name.starts_with("SIG") {
+    true  => name.parse().ok(),
+    false => format!("SIG{}", name).parse().ok(),
+  }
+}
+
+fn enabled_default() -> bool {
+  true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+  *enabled
+}
+
+fn is_false(val: &bool) -> bool {
+  !*val
+}
+
+fn restart_delay() -> time::Duration {
+  time::Duration::from_secs(1)
+}
+
+/// When a task that has exited should be restarted.
+#[derive(PartialEq, Eq, Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+  #[default]
+  Never,
+  OnFailure,
+  Always,
+}
+
+impl Restart {
+  fn applies(&self, stat: &std::process::ExitStatus) -> bool {
+    match self {
+      Self::Never     => false,
+      Self::OnFailure => !stat.success(),
+      Self::Always    => true,
+    }
+  }
 }
 
 #[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct Origin {
   definition: Option<path::PathBuf>,
+  // The taskfiles which override fields of the task, by field.
+  #[serde(default)]
+  overrides: BTreeMap<String, path::PathBuf>,
 }
 
 impl Origin {
   pub fn new<P: AsRef<path::Path>>(def: P) -> Self {
     Self{
       definition: Some(def.as_ref().into()),
+      overrides: BTreeMap::new(),
     }
   }
 
   pub fn unknown() -> Self {
     Self{
       definition: None,
+      overrides: BTreeMap::new(),
     }
   }
+
+  // The taskfile that defines a field of the task, which relative paths in
+  // the field are resolved against.
+  fn of(&self, field: &str) -> Option<&path::Path> {
+    self.overrides.get(field).or(self.definition.as_ref()).map(|e| e.as_path())
+  }
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
+/// A task to run and manage.
+#[derive(PartialEq, Eq, Serialize, Deserialize, JsonSchema, Clone)]
+#[schemars(rename = "Task", deny_unknown_fields)]
 pub struct Process {
-  #[serde(default="Origin::unknown")]
+  #[serde(skip, default="Origin::unknown")]
   origin: Origin,
-  #[serde(rename(serialize="run", deserialize="run"))]
-  command: String,
-  #[serde(rename(serialize="name", deserialize="name"))]
+  /// The name of the task, which other tasks use to depend on it.
+  #[serde(rename(serialize="name", deserialize="name"), skip_serializing_if="Option::is_none")]
   label: Option<String>,
-  #[serde(default="Vec::new")]
+  /// The command to run with a shell. Either this or 'exec' is required.
+  #[serde(rename(serialize="run", deserialize="run"), default="String::new", skip_serializing_if="String::is_empty")]
+  command: String,
+  /// A program and its arguments to run directly, without a shell.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  exec: Vec<String>,
+  /// The directory to run in, relative to the taskfile.
+  #[serde(skip_serializing_if="Option::is_none")]
+  dir: Option<String>,
+  /// The shell and its arguments used to run the command; 'sh' by default.
+  #[serde(skip_serializing_if="Option::is_none")]
+  shell: Option<String>,
+  /// The names of tasks that must be available before this one is started.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
   deps: Vec<String>,
-  #[serde(default="Vec::new")]
-  checks: Vec<String>,
+  /// Availability checks, which must all pass for the task to be available.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  checks: Vec<waiter::Check>,
+  /// How long to wait for the task to become available.
   #[serde(with = "humantime_serde", default="wait_default")]
+  #[schemars(schema_with = "schema::duration")]
   wait: time::Duration,
-  #[serde(default="HashMap::new")]
+  /// Environment variables to set for the task.
+  #[serde(default, skip_serializing_if="HashMap::is_empty")]
   env: HashMap<String, String>,
+  /// When to restart the task after it exits. Requires version 2.
+  #[serde(skip_serializing_if="Option::is_none")]
+  restart: Option<Restart>,
+  /// Whether the task is run; a disabled task can be enabled by a profile.
+  #[serde(default="enabled_default", skip_serializing_if="is_enabled")]
+  enabled: bool,
+  /// The number of instances of the task to run, which are named like '<name>.<n>'.
+  #[serde(skip_serializing_if="Option::is_none")]
+  #[schemars(range(min = 1))]
+  replicas: Option<u32>,
+  /// The name of a template this task is based on.
+  #[serde(skip_serializing_if="Option::is_none")]
+  extends: Option<String>,
+  /// Names of ports to allocate for the task, which it can refer to like '${port.<name>}'.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  ports: Vec<String>,
+  /// Values provided to tasks which depend on this one, which they can refer to like '${<task>.<name>}'.
+  #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+  exports: BTreeMap<String, export::Export>,
+  /// Files to watch, which restart the task when they change.
+  #[serde(skip_serializing_if="Option::is_none")]
+  watch: Option<watch::Watch>,
+  /// The signal the task is stopped with, like 'SIGINT'; 'SIGTERM' by default.
+  #[serde(skip_serializing_if="Option::is_none")]
+  stop_signal: Option<String>,
+  /// Signals forwarded to the task when psctl receives them: any of 'SIGHUP', 'SIGUSR1', 'SIGUSR2' and 'SIGQUIT'. Except for 'SIGHUP', these are forwarded to every task unless some tasks list them.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  signals: Vec<String>,
+  /// Resource limits and the scheduling priority of the task.
+  #[serde(skip_serializing_if="Option::is_none")]
+  limits: Option<limits::Limits>,
+  /// Run the task in a cgroup of its own, with the provided memory and CPU limits; everything left in it is killed when the task stops. Requires cgroup v2, delegated to psctl. Linux only.
+  #[serde(skip_serializing_if="Option::is_none")]
+  cgroup: Option<cgroup::Cgroup>,
+  /// The user to run the task as, by name or id. Running as another user requires root.
+  #[serde(skip_serializing_if="Option::is_none")]
+  user: Option<String>,
+  /// The group to run the task as, by name or id; the user's primary group by default.
+  #[serde(skip_serializing_if="Option::is_none")]
+  group: Option<String>,
+  /// Supplementary groups to run the task with, by name or id.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  groups: Vec<String>,
+  /// The mask files the task creates are created with, in octal, like '027'.
+  #[serde(skip_serializing_if="Option::is_none")]
+  umask: Option<String>,
+  /// A command run before the task is started, with its environment and in its directory.
+  #[serde(skip_serializing_if="Option::is_none")]
+  before: Option<Hook>,
+  /// A command run once the task is available.
+  #[serde(skip_serializing_if="Option::is_none")]
+  after_available: Option<Hook>,
+  /// A command run after the task exits or is stopped; when it exited on its own, its exit code is provided as 'PSCTL_EXIT_CODE'.
+  #[serde(skip_serializing_if="Option::is_none")]
+  after_exit: Option<Hook>,
+  /// Whether the pod runs until this task exits, and exits with its status; any other task that fails before it exits is an error. Only one task can be the main task.
+  #[serde(default, skip_serializing_if="is_false")]
+  main: bool,
+  /// Whether the pod finishes when this task exits. By default, every task is critical when the pod finishes on the first exit, and none is otherwise.
+  #[serde(skip_serializing_if="Option::is_none")]
+  critical: Option<bool>,
 }
 
 impl Process {
   pub fn new(label: Option<&str>, cmd: &str, deps: Vec<&str>, url: Option<&str>) -> Process {
-    return Self::new_with_origin(label, cmd, deps, url, Origin::unknown())
+    Self::new_with_origin(label, cmd, deps, url, Origin::unknown())
   }
 
+  #[allow(clippy::redundant_field_names)]
   pub fn new_with_origin(label: Option<&str>, cmd: &str, deps: Vec<&str>, url: Option<&str>, origin: Origin) -> Process {
     Process{
       origin: origin,
       command: cmd.to_owned(),
+      exec: Vec::new(),
+      dir: None,
+      shell: None,
       label: label.map(|label| label.to_owned()),
       deps: deps.iter().map(|e| e.to_string()).collect(),
       checks: match url {
-        Some(url) => vec![url.to_owned()],
+        Some(url) => vec![waiter::Check::new(url)],
         None => vec![],
       },
       wait: wait_default(),
       env: HashMap::new(),
+      restart: None,
+      enabled: true,
+      replicas: None,
+      extends: None,
+      ports: Vec::new(),
+      exports: BTreeMap::new(),
+      watch: None,
+      stop_signal: None,
+      signals: Vec::new(),
+      limits: None,
+      cgroup: None,
+      user: None,
+      group: None,
+      groups: Vec::new(),
+      umask: None,
+      before: None,
+      after_available: None,
+      after_exit: None,
+      main: false,
+      critical: None,
     }
   }
 
@@ -255,6 +1054,78 @@ impl Process {
     dup
   }
 
+  // Merge the named fields of another definition of this task into this one.
+  // Environment variables are merged individually; anything else is replaced.
+  pub fn overlay(&self, over: &Process, fields: &[String]) -> Self {
+    let mut dup = self.clone();
+    for field in fields {
+      match field.as_str() {
+        "name"   => dup.label = over.label.clone(),
+        "run"    => {
+          dup.command = over.command.clone();
+          dup.exec = Vec::new();
+        },
+        "exec"   => {
+          dup.exec = over.exec.clone();
+          dup.command = String::new();
+        },
+        "dir"    => dup.dir = over.dir.clone(),
+        "shell"  => dup.shell = over.shell.clone(),
+        "deps"   => dup.deps = over.deps.clone(),
+        "checks" => dup.checks = over.checks.clone(),
+        "wait"   => dup.wait = over.wait,
+        "env"    => dup.env.extend(over.env.clone()),
+        "restart" => dup.restart = over.restart,
+        "enabled" => dup.enabled = over.enabled,
+        "replicas" => dup.replicas = over.replicas,
+        "extends" => dup.extends = over.extends.clone(),
+        "ports"  => dup.ports = over.ports.clone(),
+        "exports" => dup.exports = over.exports.clone(),
+        "watch"  => dup.watch = over.watch.clone(),
+        "stop_signal" => dup.stop_signal = over.stop_signal.clone(),
+        "signals" => dup.signals = over.signals.clone(),
+        "limits" => dup.limits = over.limits.clone(),
+        "cgroup" => dup.cgroup = over.cgroup.clone(),
+        "user"   => dup.user = over.user.clone(),
+        "group"  => dup.group = over.group.clone(),
+        "groups" => dup.groups = over.groups.clone(),
+        "umask"  => dup.umask = over.umask.clone(),
+        "before" => dup.before = over.before.clone(),
+        "after_available" => dup.after_available = over.after_available.clone(),
+        "after_exit" => dup.after_exit = over.after_exit.clone(),
+        "main"   => dup.main = over.main,
+        "critical" => dup.critical = over.critical,
+        _        => continue,
+      }
+      if let Some(def) = over.origin.of(field) {
+        dup.origin.overrides.insert(field.to_owned(), def.to_path_buf());
+      }
+    }
+    dup
+  }
+
+  pub fn with_env(&self, env: &HashMap<String, String>) -> Self {
+    let mut dup = self.clone();
+    dup.env.extend(env.clone());
+    dup
+  }
+
+  pub fn with_enabled(&self, enabled: bool) -> Self {
+    let mut dup = self.clone();
+    dup.enabled = enabled;
+    dup
+  }
+
+  pub fn with_namespace(&self, ns: &str, local: &HashSet<String>) -> Self {
+    let mut dup = self.clone();
+    dup.label = self.label.as_ref().map(|e| format!("{}.{}", ns, e));
+    dup.deps = self.deps.iter().map(|e| match local.contains(e) {
+      true  => format!("{}.{}", ns, e),
+      false => e.to_owned(),
+    }).collect();
+    dup
+  }
+
   // <label> [+ <dep1> [, ...]]: <command>=<check url>
   pub fn parse(text: &str) -> Result<Process> {
     let split: Vec<&str> = text.splitn(2, ":").collect();
@@ -286,10 +1157,13 @@ impl Process {
     Ok(Self::new(label, cmd, deps, check))
   }
 
-  fn key(&self) -> &str {
+  pub fn key(&self) -> &str {
     match self.label() {
       Some(label) => label,
-      None => self.command(),
+      None => match self.exec.first() {
+        Some(prog) => prog,
+        None => self.command(),
+      },
     }
   }
 
@@ -319,10 +1193,89 @@ impl Process {
     &self.command
   }
 
-  pub fn checks(&self) -> &Vec<String> {
+  pub fn checks(&self) -> &Vec<waiter::Check> {
     &self.checks
   }
 
+  pub fn restart(&self) -> Option<Restart> {
+    self.restart
+  }
+
+  pub fn enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn extends(&self) -> Option<&str> {
+    self.extends.as_deref()
+  }
+
+  pub fn ports(&self) -> &Vec<String> {
+    &self.ports
+  }
+
+  pub fn exports(&self) -> &BTreeMap<String, export::Export> {
+    &self.exports
+  }
+
+  // The signal a task is stopped with.
+  fn stop_signal(&self) -> Result<Signal> {
+    match &self.stop_signal {
+      Some(name) => match parse_signal(name) {
+        Some(sig) => Ok(sig),
+        None => Err(error::ExecError::new(&format!("Invalid stop signal: {}: {}", name, self.key())).into()),
+      },
+      None => Ok(Signal::SIGTERM),
+    }
+  }
+
+  // The signals forwarded to the task, besides those every task receives.
+  fn signals(&self) -> Vec<Signal> {
+    signals::parse(self.key(), &self.signals).unwrap_or_default()
+  }
+
+  // Resolve a path relative to the directory the task runs in.
+  fn path(&self, path: &str) -> path::PathBuf {
+    match self.working_dir() {
+      Some(dir) => dir.join(path),
+      None => path::PathBuf::from(path),
+    }
+  }
+
+  // Replace references in the command, environment and checks of a task.
+  // When a reference is not defined, its name is returned.
+  fn interpolate(&self, vars: &HashMap<String, String>) -> result::Result<Self, String> {
+    let mut dup = self.clone();
+    dup.command = context::interpolate(&self.command, vars)?;
+    for arg in dup.exec.iter_mut() {
+      *arg = context::interpolate(arg, vars)?;
+    }
+    for val in dup.env.values_mut() {
+      *val = context::interpolate(val, vars)?;
+    }
+    for check in dup.checks.iter_mut() {
+      *check = check.with_url(&context::interpolate(check.url(), vars)?);
+    }
+    Ok(dup)
+  }
+
+  // The instances of a task. A task without replicas is its own instance;
+  // otherwise, each replica is named for its number, starting from 1, which
+  // is also provided to it as $PSCTL_REPLICA.
+  fn replicate(&self) -> Result<Vec<Process>> {
+    let n = match self.replicas {
+      Some(0) => return Err(error::ExecError::new(&format!("Task must have at least one replica: {}", self.key())).into()),
+      Some(n) => n,
+      None => return Ok(vec![self.clone()]),
+    };
+    Ok((1..=n).map(|i| {
+      let mut dup = self.clone();
+      dup.label = Some(format!("{}.{}", self.key(), i));
+      dup.env.insert("PSCTL_REPLICA".to_string(), i.to_string());
+      dup.replicas = None;
+      dup
+    }).collect())
+  }
+
   pub async fn _exec(&self) -> Result<()> {
     match self._proc()?.wait().await {
       Ok(_stat) => Ok(()),
@@ -333,24 +1286,98 @@ impl Process {
   fn _proc(&self) -> Result<process::Child> {
     match self.task()?.spawn() {
       Ok(proc) => Ok(proc),
-      Err(err) => return Err(error::ExecError::new(&format!("Could not spawn process: {}", err)).into()),
+      Err(err) => Err(error::ExecError::new(&format!("Could not spawn process: {}", err)).into()),
+    }
+  }
+
+  // The directory a task runs in: an explicit 'dir' is resolved relative to
+  // the taskfile that set it, otherwise the taskfile that defined the task.
+  fn working_dir(&self) -> Option<path::PathBuf> {
+    let base = |def: Option<&path::Path>| def.and_then(|e| e.parent()).filter(|e| !e.as_os_str().is_empty()).map(|e| e.to_path_buf()); // a bare filename is in the current directory
+    match &self.dir {
+      Some(dir) => match base(self.origin.of("dir")) {
+        Some(base) => Some(base.join(dir)), // joining an absolute path replaces the base
+        None => Some(path::PathBuf::from(dir)),
+      },
+      None => base(self.origin.definition.as_deref()),
     }
   }
 
   fn task(&self) -> Result<process::Command> {
-    let mut cmd = std::process::Command::new("sh");
+    self.task_in(None)
+  }
+
+  // The command that runs a task, which is placed in its cgroup, if it has
+  // one, as it starts.
+  fn task_in(&self, group: Option<cgroup::Group>) -> Result<process::Command> {
+    let mut cmd = match (self.command().is_empty(), self.exec.split_first()) {
+      (true, Some((prog, args))) => {
+        let mut cmd = std::process::Command::new(prog);
+        cmd.args(args);
+        cmd
+      },
+      (false, None) => {
+        let shell = match &self.shell {
+          Some(shell) => shell.split_whitespace().collect(),
+          None => vec!["sh"],
+        };
+        let (prog, args) = match shell.split_first() {
+          Some(split) => split,
+          None => return Err(error::ExecError::new(&format!("Shell is empty: {}", self)).into()),
+        };
+        let mut cmd = std::process::Command::new(prog);
+        cmd.args(args);
+        cmd.arg("-c").arg(self.command());
+        cmd
+      },
+      (true, None) => return Err(error::ExecError::new(&format!("Task defines neither 'run' nor 'exec': {}", self.key())).into()),
+      (false, Some(_)) => return Err(error::ExecError::new(&format!("Task defines both 'run' and 'exec': {}", self.key())).into()),
+    };
+    if self.shell.is_some() && !self.exec.is_empty() {
+      return Err(error::ExecError::new(&format!("Task defines 'shell' but runs with 'exec': {}", self.key())).into());
+    }
+    for (i, port) in self.ports.iter().enumerate() {
+      if port.is_empty() || !port.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(error::ExecError::new(&format!("Invalid port name: {}; names may contain letters, numbers, '_' and '-': {}", port, self.key())).into());
+      }
+      if self.ports[..i].contains(port) {
+        return Err(error::ExecError::new(&format!("Port is declared more than once: {}: {}", port, self.key())).into());
+      }
+    }
+    for (name, export) in &self.exports {
+      if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(error::ExecError::new(&format!("Invalid export name: {}; names may contain letters, numbers, '_' and '-': {}", name, self.key())).into());
+      }
+      export.validate(self.key(), name)?;
+    }
+    for check in &self.checks {
+      check.validate()?;
+    }
+    self.stop_signal()?;
+    signals::parse(self.key(), &self.signals)?;
+    if let Some(watch) = &self.watch {
+      watch.validate(self)?;
+    }
+    if let Some(limits) = &self.limits {
+      limits.validate(self.key())?;
+      limits.apply(&mut cmd);
+    }
+    if let Some(cgroup) = &self.cgroup {
+      cgroup.validate(self.key())?;
+    }
+    if let Some(group) = group {
+      group.attach(&mut cmd);
+    }
+    credentials::Credentials::resolve(self.key(), self.user.as_deref(), self.group.as_deref(), &self.groups, self.umask.as_deref())?.apply(&mut cmd); // after limits and the cgroup, which may need privileges
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
     cmd.process_group(0); // use a process group to clean up children; providing '0' uses this process' id for the group
-    if let Some(def) = &self.origin.definition {
-      if let Some(dir) = def.parent() {
-        cmd.current_dir(dir);
-      }
+    if let Some(dir) = self.working_dir() {
+      cmd.current_dir(dir);
     }
     for (key, val) in self.env.iter() {
       cmd.env(key, val);
     }
-    cmd.arg("-c").arg(self.command());
     Ok(cmd.into())
   }
 }
@@ -361,7 +1388,10 @@ impl fmt::Display for Process {
     if let Some(l) = self.label() {
       d.push_str(&format!("{}: ", l));
     }
-    d.push_str(self.command());
+    match self.exec.is_empty() {
+      true  => d.push_str(self.command()),
+      false => d.push_str(&self.exec.join(" ")),
+    }
     match self.checks.len() {
       0 => {},
       1 => d.push_str(&format!(" ({})", self.checks[0])),
@@ -377,6 +1407,58 @@ impl fmt::Debug for Process {
   }
 }
 
+/// Check that a set of tasks can be run, without running them: that each
+/// task can be executed, that their dependencies can be resolved, and that
+/// the exit policy only refers to tasks which are defined.
+pub fn validate(procs: &[Process], policy: &ExitPolicy) -> Result<()> {
+  main_task(procs)?;
+  let procs = replicate(procs)?;
+  let ord = order_procs(procs.iter().collect())?;
+  check_procs(&ord)?;
+  policy.validate(&ord)
+}
+
+// The task marked as the main task, if there is one; more than one is an
+// error.
+fn main_task(procs: &[Process]) -> Result<Option<String>> {
+  let main: Vec<&str> = procs.iter().filter(|e| e.main).map(|e| e.key()).collect();
+  match main.as_slice() {
+    [] => Ok(None),
+    [main] => Ok(Some(main.to_string())),
+    _ => Err(error::ExecError::new(&format!("Only one task can be the main task: {}", main.join(", "))).into()),
+  }
+}
+
+// Check that each task can be run and only refers to values it can access.
+fn check_procs(procs: &[&Process]) -> Result<()> {
+  let ctx = context::Context::placeholder(procs);
+  for proc in procs {
+    ctx.resolve(proc)?.task()?;
+  }
+  Ok(())
+}
+
+// Expand tasks into their replicas. A dependency on a replicated task is a
+// dependency on every one of its replicas.
+fn replicate(procs: &[Process]) -> Result<Vec<Process>> {
+  let mut names: HashMap<String, Vec<String>> = HashMap::new();
+  let mut res: Vec<Process> = Vec::new();
+  for proc in procs {
+    let inst = proc.replicate()?;
+    if proc.replicas.is_some() {
+      names.insert(proc.key().to_owned(), inst.iter().map(|e| e.key().to_owned()).collect());
+    }
+    res.extend(inst);
+  }
+  for proc in res.iter_mut() {
+    proc.deps = proc.deps.iter().flat_map(|e| match names.get(e) {
+      Some(inst) => inst.clone(),
+      None => vec![e.to_owned()],
+    }).collect();
+  }
+  Ok(res)
+}
+
 fn order_procs<'a>(procs: Vec<&'a Process>) -> Result<Vec<&'a Process>> {
   let mut ord: Vec<&'a Process> = Vec::new();
   let mut vis: HashSet<String> = HashSet::new();
@@ -394,17 +1476,14 @@ fn order_procs<'a>(procs: Vec<&'a Process>) -> Result<Vec<&'a Process>> {
 }
 
 fn order_procs_sub<'a>(proc: &'a Process, set: &HashMap<String, &'a Process>, run: &mut HashSet<String>, vis: &mut HashSet<String>, path: &mut Vec<&'a Process>) -> Result<Vec<&'a Process>> {
-  let key = match proc.label() {
-    Some(label) => label,
-    None => proc.command(),
-  };
+  let key = proc.key();
 
   let mut ord: Vec<&'a Process> = Vec::new();
   if !vis.contains(key) {
     for dep in proc.deps() {
       path.push(proc);
       if run.contains(dep) {
-        return Err(error::DependencyError::Cycle(format!("{}", path.iter().map(|e| e.key()).collect::<Vec<&str>>().join(" → "))).into());
+        return Err(error::DependencyError::Cycle(path.iter().map(|e| e.key()).collect::<Vec<&str>>().join(" → ")).into());
       }
       run.insert(dep.to_owned());
       match set.get(dep) {
@@ -454,4 +1533,150 @@ mod tests {
     };
   }
 
+  #[test]
+  fn test_overlay() {
+    let mut base = Process::new(Some("p1"), "proc 1", vec!["p0"], Some("shell:true"));
+    base.env.insert("A".to_string(), "a".to_string());
+    base.env.insert("B".to_string(), "b".to_string());
+
+    let mut over = Process::new(Some("p1"), "", vec![], None);
+    over.exec = vec!["proc".to_string(), "2".to_string()];
+    over.env.insert("B".to_string(), "B".to_string());
+
+    let res = base.overlay(&over, &["name".to_string(), "exec".to_string(), "env".to_string()]);
+    assert_eq!("", res.command());
+    assert_eq!(vec!["proc", "2"], res.exec);
+    assert_eq!(&vec!["p0".to_string()], res.deps());
+    assert_eq!(&vec![waiter::Check::new("shell:true")], res.checks());
+    assert_eq!(Some(&"a".to_string()), res.env.get("A"));
+    assert_eq!(Some(&"B".to_string()), res.env.get("B"));
+
+    let mut base = Process::new_with_origin(Some("api"), "./api", vec![], None, Origin::new("/srv/app/psctl.yaml"));
+    base.dir = Some("api".to_string());
+    let mut over = Process::new_with_origin(Some("api"), "", vec![], None, Origin::new("/home/dev/psctl.yaml"));
+    assert_eq!(Some(path::PathBuf::from("/srv/app/api")), base.overlay(&over, &["name".to_string()]).working_dir());
+    over.dir = Some("src/api".to_string());
+    assert_eq!(Some(path::PathBuf::from("/home/dev/src/api")), base.overlay(&over, &["name".to_string(), "dir".to_string()]).working_dir());
+    base.dir = None;
+    assert_eq!(Some(path::PathBuf::from("/srv/app")), base.overlay(&over, &["name".to_string()]).working_dir());
+  }
+
+  #[test]
+  fn test_replicate() {
+    let mut p1 = Process::new(Some("worker"), "work", vec![], None);
+    p1.replicas = Some(3);
+    let p2 = Process::new(Some("api"), "api", vec!["worker", "db"], None);
+
+    let res = replicate(&[p1, p2]).unwrap();
+    assert_eq!(vec!["worker.1", "worker.2", "worker.3", "api"], res.iter().map(|e| e.key()).collect::<Vec<&str>>());
+    assert_eq!(Some(&"2".to_string()), res[1].env.get("PSCTL_REPLICA"));
+    assert_eq!(&vec!["worker.1".to_string(), "worker.2".to_string(), "worker.3".to_string(), "db".to_string()], res[3].deps());
+  }
+
+  #[test]
+  fn test_affected() {
+    let mut p1 = Process::new(Some("p1"), "proc 1", vec![], None);
+    let p2 = Process::new(Some("p2"), "proc 2", vec!["p1"], None);
+    let p3 = Process::new(Some("p3"), "proc 3", vec!["p2"], None);
+    let p4 = Process::new(Some("p4"), "proc 4", vec![], None);
+    p1.watch = Some(serde_yaml::from_str("{paths: [src]}").unwrap());
+
+    let ord = vec![&p1, &p2, &p3, &p4];
+    assert_eq!(vec![0], affected(&ord, &[0]));
+    p1.watch = Some(serde_yaml::from_str("{paths: [src], dependents: true}").unwrap());
+    let ord = vec![&p1, &p2, &p3, &p4];
+    assert_eq!(vec![0, 1, 2], affected(&ord, &[0]));
+  }
+
+  #[tokio::test]
+  async fn test_signal_at_startup() {
+    use clap::Parser;
+    let ready = std::env::temp_dir().join(format!("psctl-test-startup-{}", std::process::id()));
+    let _ = std::fs::remove_file(&ready);
+    let run = format!("trap '' USR1; sleep 0.3; touch {}; sleep 0.3", ready.display());
+    let mut api = Process::new(Some("api"), &run, vec![], None);
+    api.checks = vec![serde_yaml::from_str(&format!("{{url: 'shell:test -e {}', interval: 10ms}}", ready.display())).unwrap()];
+    let pod = Pod::new(config::Options::parse_from(["psctl", "-q"]), vec![api]).unwrap();
+    let mut forward = signals::Forward::new().unwrap();
+    let (_tx, mut rx) = mpsc::channel(1);
+    let send = tokio::spawn(async {
+      tokio::time::sleep(time::Duration::from_millis(100)).await;
+      signal::kill(Pid::this(), Signal::SIGUSR1).unwrap(); // while the check is waiting
+    });
+    assert_eq!(0, pod.exec(&mut forward, &mut rx).await.unwrap());
+    send.await.unwrap();
+    let _ = std::fs::remove_file(&ready);
+  }
+
+  #[test]
+  fn test_main_task() {
+    let p1 = Process::new(Some("p1"), "proc 1", vec![], None);
+    let mut p2 = Process::new(Some("p2"), "proc 2", vec!["p1"], None);
+    assert_eq!(None, main_task(&[p1.clone(), p2.clone()]).unwrap());
+    p2.main = true;
+    assert_eq!(Some("p2".to_string()), main_task(&[p1.clone(), p2.clone()]).unwrap());
+    let mut p3 = Process::new(Some("p3"), "proc 3", vec![], None);
+    p3.main = true;
+    assert!(main_task(&[p1, p2, p3]).is_err());
+  }
+
+  #[test]
+  fn test_diff() {
+    let p1 = Process::new(Some("p1"), "proc 1", vec![], None);
+    let p2 = Process::new(Some("p2"), "proc 2", vec!["p1"], None);
+    let p3 = Process::new(Some("p3"), "proc 3", vec!["p2"], None);
+    let p4 = Process::new(Some("p4"), "proc 4", vec![], None);
+    let prev = vec![&p1, &p2, &p3, &p4];
+
+    let next = vec![p1.clone(), p2.clone(), p3.clone(), p4.clone()];
+    assert_eq!(Diff::default(), Diff::from_specs(&prev, &next));
+
+    let mut c2 = p2.clone();
+    c2.command = "proc 2 --verbose".to_string();
+    let p5 = Process::new(Some("p5"), "proc 5", vec!["p1"], None);
+    let next = vec![p1.clone(), c2, p3.clone(), p5];
+    assert_eq!(Diff{
+      added: vec!["p5".to_string()],
+      removed: vec!["p4".to_string()],
+      restart: vec!["p2".to_string(), "p3".to_string()],
+    }, Diff::from_specs(&prev, &next));
+  }
+
+  #[test]
+  fn test_task_command() {
+    let origin = Origin::new("/srv/app/psctl.yaml");
+
+    let mut p1 = Process::new_with_origin(Some("p1"), "echo $HOME", vec![], None, origin.clone());
+    let cmd = p1.task().unwrap();
+    let cmd = cmd.as_std();
+    assert_eq!("sh", cmd.get_program());
+    assert_eq!(vec!["-c", "echo $HOME"], cmd.get_args().collect::<Vec<_>>());
+    assert_eq!(Some(path::Path::new("/srv/app")), cmd.get_current_dir());
+
+    p1.shell = Some("bash -euo pipefail".to_string());
+    p1.dir = Some("web".to_string());
+    let cmd = p1.task().unwrap();
+    let cmd = cmd.as_std();
+    assert_eq!("bash", cmd.get_program());
+    assert_eq!(vec!["-euo", "pipefail", "-c", "echo $HOME"], cmd.get_args().collect::<Vec<_>>());
+    assert_eq!(Some(path::Path::new("/srv/app/web")), cmd.get_current_dir());
+
+    let mut p2 = Process::new_with_origin(Some("p2"), "", vec![], None, origin);
+    p2.exec = vec!["server".to_string(), "--port".to_string(), "8080".to_string()];
+    p2.dir = Some("/var/lib/app".to_string());
+    let cmd = p2.task().unwrap();
+    let cmd = cmd.as_std();
+    assert_eq!("server", cmd.get_program());
+    assert_eq!(vec!["--port", "8080"], cmd.get_args().collect::<Vec<_>>());
+    assert_eq!(Some(path::Path::new("/var/lib/app")), cmd.get_current_dir());
+
+    p2.command = "server".to_string();
+    assert!(p2.task().is_err());
+
+    let mut p3 = Process::new_with_origin(Some("p3"), "echo $HOME", vec![], None, Origin::new("psctl.yaml"));
+    assert_eq!(None, p3.task().unwrap().as_std().get_current_dir());
+    p3.dir = Some("web".to_string());
+    assert_eq!(Some(path::Path::new("web")), p3.task().unwrap().as_std().get_current_dir());
+  }
+
 }
@@ -0,0 +1,107 @@
+use nix::sys::signal::Signal;
+use tokio::signal::unix;
+use tokio::sync::mpsc;
+
+use super::error;
+use super::parse_signal;
+use super::Process;
+use super::Result;
+
+/// The signals psctl forwards to the tasks it runs. SIGHUP is only forwarded
+/// to the tasks which ask for it, since psctl reloads its taskfile when it
+/// receives it; the others are forwarded to every task, unless some tasks ask
+/// for them, in which case they are only forwarded to those.
+pub const FORWARD: [Signal; 4] = [Signal::SIGHUP, Signal::SIGUSR1, Signal::SIGUSR2, Signal::SIGQUIT];
+
+/// Translate the signals that stop psctl, SIGINT and SIGTERM, into requests
+/// to cancel which are sent to the provided channel. The first request stops
+/// the pod gracefully; a second, sent while it is stopping, kills every task
+/// immediately.
+pub fn shutdown(tx: mpsc::Sender<()>) -> Result<()> {
+  let mut int = unix::signal(unix::SignalKind::interrupt())?;
+  let mut term = unix::signal(unix::SignalKind::terminate())?;
+  tokio::spawn(async move {
+    loop {
+      tokio::select! {
+        Some(_) = int.recv() => {},
+        Some(_) = term.recv() => {},
+        else => break,
+      }
+      let _ = tx.try_send(()); // a request that is already pending is enough
+    }
+  });
+  Ok(())
+}
+
+/// Receives the signals which are forwarded to tasks.
+pub struct Forward {
+  streams: Vec<(Signal, unix::Signal)>,
+}
+
+impl Forward {
+  #[allow(clippy::redundant_field_names)]
+  pub fn new() -> Result<Self> {
+    let mut streams: Vec<(Signal, unix::Signal)> = Vec::new();
+    for sig in FORWARD {
+      streams.push((sig, unix::signal(unix::SignalKind::from_raw(sig as i32))?));
+    }
+    Ok(Self{
+      streams: streams,
+    })
+  }
+
+  /// Wait for the next signal to forward.
+  pub async fn recv(&mut self) -> Signal {
+    let recv = self.streams.iter_mut().map(|(sig, stream)| Box::pin(async move {
+      stream.recv().await;
+      *sig
+    }));
+    futures::future::select_all(recv).await.0
+  }
+}
+
+/// Whether a task receives a signal that psctl forwards, given every task
+/// that is running.
+pub fn receives(spec: &Process, sig: Signal, procs: &[&Process]) -> bool {
+  if spec.signals().contains(&sig) {
+    return true;
+  }
+  sig != Signal::SIGHUP && !procs.iter().any(|e| e.signals().contains(&sig))
+}
+
+/// Parse the signals a task asks for, which must be ones that are forwarded.
+pub fn parse(key: &str, names: &[String]) -> Result<Vec<Signal>> {
+  let mut res: Vec<Signal> = Vec::new();
+  for name in names {
+    match parse_signal(name) {
+      Some(sig) if FORWARD.contains(&sig) => res.push(sig),
+      _ => return Err(error::ExecError::new(&format!("Signal cannot be forwarded: {}; signals which can be forwarded are: {}: {}", name, FORWARD.map(|e| e.as_str()).join(", "), key)).into()),
+    }
+  }
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_receives() {
+    let mut nginx = Process::new(Some("nginx"), "nginx", vec![], None);
+    let api = Process::new(Some("api"), "api", vec![], None);
+    let procs = vec![&nginx, &api];
+    assert!(receives(&nginx, Signal::SIGUSR1, &procs));
+    assert!(receives(&api, Signal::SIGUSR1, &procs));
+    assert!(!receives(&api, Signal::SIGHUP, &procs));
+
+    nginx.signals = vec!["USR1".to_string(), "SIGHUP".to_string()];
+    let procs = vec![&nginx, &api];
+    assert!(receives(&nginx, Signal::SIGUSR1, &procs));
+    assert!(!receives(&api, Signal::SIGUSR1, &procs));
+    assert!(receives(&nginx, Signal::SIGHUP, &procs));
+    assert!(receives(&api, Signal::SIGQUIT, &procs));
+
+    assert!(parse("nginx", &["SIGINT".to_string()]).is_err());
+  }
+
+}
@@ -0,0 +1,169 @@
+use core::time;
+
+use std::env;
+use std::path;
+use std::result;
+
+use notify::Watcher;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use tokio::sync::mpsc;
+
+use crate::schema;
+
+use super::error;
+use super::Process;
+use super::Result;
+
+fn debounce_default() -> time::Duration {
+  time::Duration::from_millis(500)
+}
+
+/// Files to watch, which restart a task when they change.
+#[derive(PartialEq, Eq, Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct Watch {
+  /// Paths or globs to watch, relative to the taskfile. A directory is watched along with everything in it.
+  paths: Vec<String>,
+  /// Globs for paths to ignore, relative to the taskfile.
+  #[serde(default, skip_serializing_if="Vec::is_empty")]
+  ignore: Vec<String>,
+  /// How long to wait for changes to settle before restarting.
+  #[serde(with = "humantime_serde", default="debounce_default")]
+  #[schemars(schema_with = "schema::duration")]
+  debounce: time::Duration,
+  /// Whether tasks that depend on this one are restarted too.
+  #[serde(default)]
+  dependents: bool,
+}
+
+impl Watch {
+  pub fn debounce(&self) -> time::Duration {
+    self.debounce
+  }
+
+  pub fn dependents(&self) -> bool {
+    self.dependents
+  }
+
+  /// Check that the paths to watch are valid patterns.
+  pub fn validate(&self, spec: &Process) -> Result<()> {
+    Paths::new(spec, self)?;
+    Ok(())
+  }
+}
+
+// The paths a task watches, resolved relative to its taskfile.
+struct Paths {
+  watch: Vec<glob::Pattern>,
+  ignore: Vec<glob::Pattern>,
+  roots: Vec<path::PathBuf>,
+}
+
+impl Paths {
+  fn new(spec: &Process, conf: &Watch) -> Result<Self> {
+    let base = match spec.origin.of("watch") {
+      Some(def) => def.parent().map(|e| e.to_path_buf()).unwrap_or_default(),
+      None => path::PathBuf::new(),
+    };
+    let base = match base.as_os_str().is_empty() { // changes are reported with absolute paths
+      true  => env::current_dir()?,
+      false => path::absolute(&base)?,
+    };
+    let pattern = |e: &String| {
+      let path = base.join(e);
+      match glob::Pattern::new(&path.to_string_lossy()) {
+        Ok(pattern) => Ok(pattern),
+        Err(err) => Err(error::ExecError::new(&format!("{}: Invalid watch pattern: {}: {}", spec.key(), e, err))),
+      }
+    };
+    Ok(Self{
+      watch: conf.paths.iter().map(pattern).collect::<result::Result<_, _>>()?,
+      ignore: conf.ignore.iter().map(pattern).collect::<result::Result<_, _>>()?,
+      roots: conf.paths.iter().map(|e| root(&base.join(e))).collect(),
+    })
+  }
+
+  // Whether a changed path is one the task watches. A path is watched when
+  // it matches a pattern or is within a watched directory.
+  fn matches(&self, path: &path::Path) -> bool {
+    if self.ignore.iter().any(|e| e.matches_path(path) || path.ancestors().any(|a| e.matches_path(a))) {
+      return false;
+    }
+    self.watch.iter().any(|e| e.matches_path(path) || path.ancestors().any(|a| e.matches_path(a)))
+  }
+}
+
+// The directory or file to watch for a path, which is the part of it that
+// comes before any glob.
+fn root(path: &path::Path) -> path::PathBuf {
+  let mut root = path::PathBuf::new();
+  for part in path.components() {
+    let text = part.as_os_str().to_string_lossy();
+    if text.contains(['*', '?', '[']) {
+      break;
+    }
+    root.push(part);
+  }
+  root
+}
+
+/// Watch the files of every task that has a 'watch' section. The index of a
+/// task is sent whenever one of its files changes. Files are watched for as
+/// long as the returned watchers are kept.
+pub fn watch(procs: &[&Process], tx: mpsc::UnboundedSender<usize>) -> Result<Vec<notify::RecommendedWatcher>> {
+  let mut watchers: Vec<notify::RecommendedWatcher> = Vec::new();
+  for (i, spec) in procs.iter().enumerate() {
+    let conf = match &spec.watch {
+      Some(conf) => conf,
+      None => continue,
+    };
+    let paths = Paths::new(spec, conf)?;
+    let roots = paths.roots.clone();
+    let tx = tx.clone();
+    let fail = |err: notify::Error| error::ExecError::new(&format!("{}: Could not watch files: {}", spec.key(), err));
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+      if let Ok(event) = res {
+        if !event.kind.is_access() && event.paths.iter().any(|e| paths.matches(e)) {
+          let _ = tx.send(i); // the receiver is gone once the pod has finished
+        }
+      }
+    }).map_err(fail)?;
+    for root in &roots {
+      let mode = match root.is_dir() {
+        true  => notify::RecursiveMode::Recursive,
+        false => notify::RecursiveMode::NonRecursive,
+      };
+      watcher.watch(root, mode).map_err(fail)?;
+    }
+    watchers.push(watcher);
+  }
+  Ok(watchers)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_matches() {
+    let mut spec = Process::new_with_origin(Some("api"), "api", vec![], None, super::super::Origin::new("/srv/app/psctl.yaml"));
+    let conf = Watch{
+      paths: vec!["src/**/*.rs".to_string(), "Cargo.toml".to_string(), "static".to_string()],
+      ignore: vec!["src/gen".to_string()],
+      debounce: debounce_default(),
+      dependents: false,
+    };
+    spec.watch = Some(conf.clone());
+
+    let paths = Paths::new(&spec, &conf).unwrap();
+    assert_eq!(vec![path::PathBuf::from("/srv/app/src"), path::PathBuf::from("/srv/app/Cargo.toml"), path::PathBuf::from("/srv/app/static")], paths.roots);
+    assert!(paths.matches(path::Path::new("/srv/app/src/main.rs")));
+    assert!(paths.matches(path::Path::new("/srv/app/src/runner/mod.rs")));
+    assert!(paths.matches(path::Path::new("/srv/app/Cargo.toml")));
+    assert!(paths.matches(path::Path::new("/srv/app/static/css/app.css")));
+    assert!(!paths.matches(path::Path::new("/srv/app/src/gen/schema.rs")));
+    assert!(!paths.matches(path::Path::new("/srv/app/README.md")));
+  }
+
+}
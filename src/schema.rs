@@ -0,0 +1,233 @@
+use std::fmt;
+
+use schemars::{Schema, SchemaGenerator, schema_for, json_schema};
+use serde_json::Value as Json;
+use serde_yaml::Value;
+
+use crate::taskfile;
+
+/// The schemes that availability checks can use.
+pub const CHECK_SCHEMES: [&str; 5] = ["http", "https", "file", "shell", "listen"];
+
+/// Generate the JSON Schema for a taskfile.
+pub fn taskfile() -> Schema {
+  schema_for!(taskfile::SpecFile)
+}
+
+/// The schema for a duration, like '30s' or '1m 30s', as it's parsed by humantime.
+pub fn duration(_: &mut SchemaGenerator) -> Schema {
+  json_schema!({
+    "type": "string",
+    "pattern": r"^\s*([0-9]+\s*[a-zA-Zµ]+\s*)+$",
+    "description": "A duration, like: 250ms, 30s, 1m 30s",
+  })
+}
+
+/// The schema for an availability check URL.
+pub fn check_url(_: &mut SchemaGenerator) -> Schema {
+  json_schema!({
+    "type": "string",
+    "pattern": format!("^({}):", CHECK_SCHEMES.join("|")),
+    "description": format!("An availability check URL, using one of the schemes: {}", CHECK_SCHEMES.join(", ")),
+  })
+}
+
+/// A field in a document which its schema does not describe.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownField {
+  /// Where the field is, like 'tasks[1]', or empty at the top level.
+  pub location: String,
+  /// The name of the task the field is within, if any.
+  pub task: Option<String>,
+  /// The name of the field.
+  pub name: String,
+  /// The closest known field, if there is one that is similar enough.
+  pub suggestion: Option<String>,
+}
+
+impl fmt::Display for UnknownField {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Unknown field '{}'", self.name)?;
+    if !self.location.is_empty() {
+      write!(f, " in {}", self.location)?;
+    }
+    if let Some(task) = &self.task {
+      write!(f, " (task: {})", task)?;
+    }
+    if let Some(suggestion) = &self.suggestion {
+      write!(f, "; did you mean '{}'?", suggestion)?;
+    }
+    Ok(())
+  }
+}
+
+/// Find the fields in a document that are not allowed by a schema. Only
+/// fields in objects which don't permit additional properties are reported;
+/// other mistakes, like values of the wrong type, are left for the parser.
+pub fn unknown_fields(schema: &Schema, doc: &Value) -> Vec<UnknownField> {
+  let root = schema.as_value();
+  let mut unknown: Vec<UnknownField> = Vec::new();
+  find_unknown(root, root, doc, "", None, &mut unknown);
+  unknown
+}
+
+fn find_unknown(root: &Json, schema: &Json, val: &Value, loc: &str, task: Option<&str>, unknown: &mut Vec<UnknownField>) {
+  let schema = match select(root, schema, val) {
+    Some(schema) => schema,
+    None => return,
+  };
+  match val {
+    Value::Sequence(items) => if let Some(item) = schema.get("items") {
+      for (i, e) in items.iter().enumerate() {
+        find_unknown(root, item, e, &format!("{}[{}]", loc, i), task, unknown);
+      }
+    },
+    Value::Mapping(fields) => {
+      let task = fields.get("name").and_then(|e| e.as_str()).or(task);
+      let props = schema.get("properties").and_then(|e| e.as_object());
+      let extra = schema.get("additionalProperties");
+      for (key, e) in fields {
+        let key = match key.as_str() {
+          Some(key) => key,
+          None => continue,
+        };
+        let sub = if loc.is_empty() { key.to_owned() } else { format!("{}.{}", loc, key) };
+        match (props.and_then(|p| p.get(key)), extra) {
+          (Some(prop), _) => find_unknown(root, prop, e, &sub, task, unknown),
+          (None, Some(Json::Bool(false))) => unknown.push(UnknownField{
+            location: loc.to_owned(),
+            task: task.map(|e| e.to_owned()),
+            name: key.to_owned(),
+            suggestion: props.and_then(|p| suggest(key, p.keys())),
+          }),
+          (None, Some(extra)) if extra.is_object() => find_unknown(root, extra, e, &sub, task, unknown),
+          (None, _) => {},
+        }
+      }
+    },
+    _ => {},
+  }
+}
+
+// Resolve references and select the alternative that describes a value of
+// the same kind, when a schema allows more than one.
+fn select<'a>(root: &'a Json, schema: &'a Json, val: &Value) -> Option<&'a Json> {
+  let schema = match schema.get("$ref").and_then(|e| e.as_str()) {
+    Some(name) => root.get("$defs")?.get(name.strip_prefix("#/$defs/")?)?,
+    None => schema,
+  };
+  let alts = match schema.get("anyOf").or_else(|| schema.get("oneOf")).and_then(|e| e.as_array()) {
+    Some(alts) => alts,
+    None => return Some(schema),
+  };
+  let kind = match val {
+    Value::Mapping(_) => "object",
+    Value::Sequence(_) => "array",
+    _ => return None,
+  };
+  alts.iter().filter_map(|e| select(root, e, val)).find(|e| match e.get("type") {
+    Some(Json::String(t)) => t == kind,
+    Some(Json::Array(t)) => t.iter().any(|t| t == kind),
+    _ => false,
+  })
+}
+
+// Suggest the known field closest to an unknown one, if any is close enough
+// that it's likely to be a typo.
+fn suggest<'a, I: Iterator<Item=&'a String>>(name: &str, known: I) -> Option<String> {
+  let limit = (name.chars().count() / 3).max(2);
+  known.map(|e| (strsim::levenshtein(name, e), e))
+    .filter(|(d, _)| *d <= limit)
+    .min_by_key(|(d, _)| *d)
+    .map(|(_, e)| e.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path;
+  use std::collections::BTreeSet;
+
+  use crate::runner;
+
+  // The published schema must be regenerated when the taskfile changes:
+  //   $ psctl schema -o psctl.schema.json
+  #[test]
+  fn test_published_schema() {
+    let published = fs::read_to_string(path::Path::new(env!("CARGO_MANIFEST_DIR")).join("psctl.schema.json")).unwrap();
+    let published: serde_json::Value = serde_json::from_str(&published).unwrap();
+    assert_eq!(published, taskfile().to_value());
+  }
+
+  #[test]
+  fn test_task_fields() {
+    let task: runner::Process = serde_yaml::from_str(r#"
+name: a
+run: echo A
+exec: [echo, A]
+dir: sub
+shell: bash
+deps: [b]
+checks: [{url: "shell:true"}]
+wait: 5s
+env: {A: a}
+restart: always
+enabled: false
+replicas: 2
+extends: base
+ports: [http]
+exports: {token: {pattern: "token=(\\S+)"}}
+watch: {paths: [src]}
+stop_signal: SIGINT
+critical: false
+main: true
+signals: [SIGUSR1]
+limits: {nofile: 1024}
+cgroup: {memory: 512M, cpu: 0.5}
+user: www-data
+group: www-data
+groups: [adm]
+umask: '027'
+before: mkdir -p run
+after_available: {run: ./seed, fatal: true}
+after_exit: cat log
+"#).unwrap();
+    let task = serde_yaml::to_value(&task).unwrap();
+    let fields: BTreeSet<&str> = task.as_mapping().unwrap().keys().map(|e| e.as_str().unwrap()).collect();
+    let schema = taskfile().to_value();
+    let props: BTreeSet<&str> = schema["$defs"]["Task"]["properties"].as_object().unwrap().keys().map(|e| e.as_str()).collect();
+    assert_eq!(props, fields);
+  }
+
+  #[test]
+  fn test_unknown_fields() {
+    let doc: Value = serde_yaml::from_str(r#"
+version: 2
+task: []
+include:
+  - common.yaml
+  - {path: api.yaml, namepsace: api}
+tasks:
+  - name: api
+    run: ./api
+    dep: [db]
+    checks:
+      - url: "http://localhost:8080/"
+        intervall: 2s
+    env: {ANYTHING: goes}
+  - name: db
+    run: ./db
+    enviroment: {A: a}
+"#).unwrap();
+    let unknown: Vec<String> = unknown_fields(&taskfile(), &doc).iter().map(|e| e.to_string()).collect();
+    assert_eq!(vec![
+      "Unknown field 'task'; did you mean 'tasks'?".to_string(),
+      "Unknown field 'namepsace' in include[1]; did you mean 'namespace'?".to_string(),
+      "Unknown field 'dep' in tasks[0] (task: api); did you mean 'deps'?".to_string(),
+      "Unknown field 'intervall' in tasks[0].checks[0] (task: api); did you mean 'interval'?".to_string(),
+      "Unknown field 'enviroment' in tasks[1] (task: db)".to_string(),
+    ], unknown);
+  }
+
+}
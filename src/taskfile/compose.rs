@@ -0,0 +1,290 @@
+use std::path;
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use super::error;
+use super::Result;
+
+/// The subset of a Compose file that can be expressed as tasks.
+#[derive(Deserialize)]
+struct ComposeFile {
+  #[serde(default="Mapping::new")]
+  services: Mapping,
+}
+
+#[derive(Deserialize)]
+struct Service {
+  command: Option<Command>,
+  depends_on: Option<DependsOn>,
+  healthcheck: Option<Healthcheck>,
+  environment: Option<Environment>,
+  working_dir: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Command {
+  Shell(String),
+  Exec(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DependsOn {
+  List(Vec<String>),
+  Conditions(BTreeMap<String, Dependency>),
+}
+
+#[derive(Deserialize)]
+struct Dependency {
+  #[serde(default="Dependency::default_condition")]
+  condition: String,
+}
+
+impl Dependency {
+  fn default_condition() -> String {
+    "service_started".to_string()
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Environment {
+  List(Vec<String>),
+  Map(BTreeMap<String, Option<Value>>),
+}
+
+#[derive(Deserialize)]
+struct Healthcheck {
+  test: Option<Command>,
+  #[serde(default)]
+  disable: bool,
+  #[serde(default, with="humantime_serde")]
+  interval: Option<std::time::Duration>,
+  #[serde(default, with="humantime_serde")]
+  timeout: Option<std::time::Duration>,
+  #[serde(default, with="humantime_serde")]
+  start_period: Option<std::time::Duration>,
+  retries: Option<u32>,
+}
+
+/// Read the services defined by a Compose file into a taskfile document,
+/// along with what couldn't be expressed in it and was left out. Services
+/// are run by their 'command' and become available when their 'healthcheck'
+/// passes. Services that don't define a command, like those that only run an
+/// image, are left out and dependencies on them dropped.
+pub fn read(path: &path::Path, data: &str) -> Result<(Value, Vec<String>)> {
+  let compose: ComposeFile = match serde_yaml::from_str(data) {
+    Ok(compose) => compose,
+    Err(err) => return Err(error::Error::YamlError(path.to_owned(), err)),
+  };
+
+  let mut services: Vec<(String, Service)> = Vec::new();
+  let mut omitted: Vec<String> = Vec::new();
+  for (name, service) in compose.services {
+    let name = match name.as_str() {
+      Some(name) => name.to_owned(),
+      None => return Err(error::Error::ImportError(path.to_owned(), format!("Invalid service name: {:?}", name))),
+    };
+    let service: Service = match serde_yaml::from_value(service) {
+      Ok(service) => service,
+      Err(err) => return Err(error::Error::ImportError(path.to_owned(), format!("Invalid service: {}: {}", name, err))),
+    };
+    match service.command {
+      Some(_) => services.push((name, service)),
+      None => omitted.push(format!("Service has no command and is not imported: {}", name)),
+    }
+  }
+
+  let mut tasks: Vec<Value> = Vec::new();
+  for (name, service) in &services {
+    let mut task = Mapping::new();
+    task.insert("name".into(), name.as_str().into());
+    match &service.command {
+      Some(Command::Shell(cmd)) => task.insert("run".into(), unescape(cmd).into()),
+      Some(Command::Exec(argv)) => task.insert("exec".into(), argv.iter().map(|e| Value::from(unescape(e))).collect()),
+      None => None,
+    };
+    if let Some(dir) = &service.working_dir {
+      task.insert("dir".into(), dir.as_str().into());
+    }
+
+    let conds: Vec<(&String, &str)> = match &service.depends_on {
+      Some(DependsOn::List(deps)) => deps.iter().map(|e| (e, "service_started")).collect(),
+      Some(DependsOn::Conditions(deps)) => deps.iter().map(|(k, v)| (k, v.condition.as_str())).collect(),
+      None => Vec::new(),
+    };
+    let mut deps: Vec<Value> = Vec::new();
+    for (dep, cond) in conds {
+      let checked = match services.iter().find(|(name, _)| name == dep) {
+        Some((_, dep)) => dep.healthcheck.as_ref().and_then(check_url).is_some(),
+        None => continue,
+      };
+      // A task waits for its dependencies to become available, which is when
+      // their checks pass, or as soon as they start if they have none.
+      match cond {
+        "service_healthy" if checked => deps.push(dep.as_str().into()),
+        "service_healthy" => return Err(error::Error::ImportError(path.to_owned(), format!("{}: Depends on {} being healthy, but it has no healthcheck", name, dep))),
+        "service_started" if !checked => deps.push(dep.as_str().into()),
+        "service_started" => omitted.push(format!("{}: Tasks can't depend on {} being started without waiting for its healthcheck; the dependency is dropped, use 'condition: service_healthy' to keep it", name, dep)),
+        cond => return Err(error::Error::ImportError(path.to_owned(), format!("{}: Dependency condition is not supported: {}: {}", name, cond, dep))),
+      }
+    }
+    if !deps.is_empty() {
+      task.insert("deps".into(), Value::Sequence(deps));
+    }
+
+    if let Some(check) = &service.healthcheck {
+      if let Some(url) = check_url(check) {
+        let mut spec = Mapping::new();
+        spec.insert("url".into(), url.into());
+        task.insert("checks".into(), Value::Sequence(vec![Value::Mapping(spec)]));
+        if let Some(wait) = check_wait(check) {
+          task.insert("wait".into(), humantime::format_duration(wait).to_string().into());
+        }
+      }
+    }
+
+    let env = match &service.environment {
+      Some(Environment::List(vars)) => vars.iter().filter_map(|e| e.split_once('=')).map(|(k, v)| (k.into(), v.into())).collect(),
+      Some(Environment::Map(vars)) => vars.iter().filter_map(|(k, v)| Some((k.as_str().into(), env_value(v.as_ref()?)?.into()))).collect(),
+      None => Mapping::new(),
+    };
+    if !env.is_empty() {
+      task.insert("env".into(), Value::Mapping(env));
+    }
+
+    tasks.push(Value::Mapping(task));
+  }
+
+  let mut doc = Mapping::new();
+  doc.insert("version".into(), super::LATEST_VERSION.into());
+  doc.insert("tasks".into(), Value::Sequence(tasks));
+  Ok((Value::Mapping(doc), omitted))
+}
+
+// A healthcheck test is either a command run with a shell or one of the
+// forms: ["NONE"], ["CMD", <arg1>, ...], ["CMD-SHELL", <command>].
+fn check_url(check: &Healthcheck) -> Option<String> {
+  if check.disable {
+    return None;
+  }
+  match check.test.as_ref()? {
+    Command::Shell(cmd) => Some(format!("shell:{}", unescape(cmd))),
+    Command::Exec(argv) => match argv.split_first()? {
+      (kind, args) if kind == "CMD" => Some(format!("shell:{}", args.iter().map(|e| shell_quote(&unescape(e))).collect::<Vec<String>>().join(" "))),
+      (kind, args) if kind == "CMD-SHELL" => Some(format!("shell:{}", unescape(&args.join(" ")))),
+      _ => None,
+    },
+  }
+}
+
+// Compose retries a healthcheck a number of times before giving up, which
+// is approximated as a deadline. Defaults are the same as Docker's.
+fn check_wait(check: &Healthcheck) -> Option<std::time::Duration> {
+  if check.interval.is_none() && check.timeout.is_none() && check.start_period.is_none() && check.retries.is_none() {
+    return None;
+  }
+  let interval = check.interval.unwrap_or(std::time::Duration::from_secs(30));
+  let timeout = check.timeout.unwrap_or(std::time::Duration::from_secs(30));
+  let start = check.start_period.unwrap_or_default();
+  Some(start + (interval + timeout) * check.retries.unwrap_or(3))
+}
+
+// Compose escapes a literal '$' as '$$', which is not necessary in a taskfile.
+fn unescape(text: &str) -> String {
+  text.replace("$$", "$")
+}
+
+fn env_value(val: &Value) -> Option<String> {
+  match val {
+    Value::String(val) => Some(val.to_owned()),
+    Value::Number(val) => Some(val.to_string()),
+    Value::Bool(val) => Some(val.to_string()),
+    _ => None,
+  }
+}
+
+fn shell_quote(arg: &str) -> String {
+  if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c)) {
+    arg.to_owned()
+  }else{
+    format!("'{}'", arg.replace('\'', "'\\''"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_read() {
+    let data = r#"
+services:
+  db:
+    image: postgres:15
+  api:
+    command: ./bin/api --port 8080
+    working_dir: ./api
+    environment:
+      PORT: 8080
+      DEBUG: "true"
+    depends_on:
+      db:
+        condition: service_healthy
+      cache:
+        condition: service_healthy
+    healthcheck:
+      test: ["CMD", "curl", "-f", "http://localhost:8080/health"]
+      interval: 5s
+      retries: 2
+  cache:
+    command: ["redis-server", "--port", "6380"]
+    environment:
+      - MODE=test
+    healthcheck:
+      test: ["CMD-SHELL", "redis-cli -p 6380 ping"]
+"#;
+    let (doc, omitted) = read(path::Path::new("compose.yaml"), data).unwrap();
+    assert_eq!(vec!["Service has no command and is not imported: db"], omitted);
+    let tasks = doc["tasks"].as_sequence().unwrap();
+    assert_eq!(2, tasks.len());
+
+    let api = &tasks[0];
+    assert_eq!(api["name"], "api");
+    assert_eq!(api["run"], "./bin/api --port 8080");
+    assert_eq!(api["dir"], "./api");
+    assert_eq!(Value::Sequence(vec!["cache".into()]), api["deps"]);
+    assert_eq!(api["checks"][0]["url"], "shell:curl -f http://localhost:8080/health");
+    assert_eq!(api["wait"], "1m 10s");
+    assert_eq!(api["env"]["PORT"], "8080");
+    assert_eq!(api["env"]["DEBUG"], "true");
+
+    let cache = &tasks[1];
+    assert_eq!(Value::Sequence(vec!["redis-server".into(), "--port".into(), "6380".into()]), cache["exec"]);
+    assert_eq!(cache["checks"][0]["url"], "shell:redis-cli -p 6380 ping");
+    assert_eq!(cache["env"]["MODE"], "test");
+
+    let data = r#"
+services:
+  migrate:
+    command: ./bin/migrate
+  api:
+    command: ./bin/api
+    depends_on: [migrate, cache]
+  cache:
+    command: redis-server
+    healthcheck:
+      test: redis-cli ping
+"#;
+    let (doc, omitted) = read(path::Path::new("compose.yaml"), data).unwrap();
+    assert_eq!(Value::Sequence(vec!["migrate".into()]), doc["tasks"][1]["deps"]);
+    assert_eq!(1, omitted.len());
+    assert!(omitted[0].starts_with("api: Tasks can't depend on cache being started"));
+    assert!(read(path::Path::new("compose.yaml"), &data.replace("[migrate, cache]", "{migrate: {condition: service_healthy}}")).is_err());
+    assert!(read(path::Path::new("compose.yaml"), &data.replace("[migrate, cache]", "{migrate: {condition: service_completed_successfully}}")).is_err());
+  }
+
+}
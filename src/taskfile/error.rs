@@ -0,0 +1,59 @@
+use std::io;
+use std::fmt;
+use std::path;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+  IOError(io::Error),
+  ReadError(path::PathBuf, io::Error),
+  YamlError(path::PathBuf, serde_yaml::Error),
+  JsonError(path::PathBuf, serde_json::Error),
+  TomlError(path::PathBuf, toml::de::Error),
+  TomlWriteError(path::PathBuf, toml::ser::Error),
+  PatternError(glob::PatternError),
+  VersionError(path::PathBuf, String),
+  IncludeError(String),
+  ImportError(path::PathBuf, String),
+  UnsupportedError(String),
+  DuplicateTaskError(String),
+  UnknownFieldError(path::PathBuf, Vec<String>),
+  ProfileError(String),
+  TemplateError(path::PathBuf, String),
+  DisabledError(String, String),
+}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Self {
+    Self::IOError(err)
+  }
+}
+
+impl From<glob::PatternError> for Error {
+  fn from(err: glob::PatternError) -> Self {
+    Self::PatternError(err)
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::IOError(err) => err.fmt(f),
+      Self::ReadError(path, err) => write!(f, "{}: {}", path.display(), err),
+      Self::YamlError(path, err) => write!(f, "{}: {}", path.display(), err),
+      Self::JsonError(path, err) => write!(f, "{}: {}", path.display(), err),
+      Self::TomlError(path, err) => write!(f, "{}: {}", path.display(), err),
+      Self::TomlWriteError(path, err) => write!(f, "{}: {}", path.display(), err),
+      Self::PatternError(err) => err.fmt(f),
+      Self::VersionError(path, msg) => write!(f, "{}: {}", path.display(), msg),
+      Self::IncludeError(msg) => write!(f, "{}", msg),
+      Self::ImportError(path, msg) => write!(f, "{}: {}", path.display(), msg),
+      Self::UnsupportedError(msg) => write!(f, "{}", msg),
+      Self::DuplicateTaskError(key) => write!(f, "Task is defined more than once: {}", key),
+      Self::ProfileError(msg) => write!(f, "{}", msg),
+      Self::TemplateError(path, msg) => write!(f, "{}: {}", path.display(), msg),
+      Self::DisabledError(key, dep) => write!(f, "{}: depends on a disabled task: {}", key, dep),
+      Self::UnknownFieldError(path, fields) => write!(f, "{}: {}", path.display(), fields.join(&format!("\n{}: ", path.display()))),
+    }
+  }
+}
@@ -0,0 +1,225 @@
+use std::fs;
+use std::path;
+
+use serde_yaml::{Mapping, Value};
+
+use super::error;
+use super::Result;
+use super::{Config, Format, LATEST_VERSION};
+
+/// The result of migrating a taskfile.
+pub struct Migration {
+  /// The version the taskfile was migrated from.
+  pub version: u32,
+  /// The migrated taskfile.
+  pub data: String,
+  /// Whether the taskfile was rewritten in place, preserving its comments
+  /// and formatting, instead of being written anew.
+  pub verbatim: bool,
+}
+
+/// Migrate a taskfile to the latest version. Where possible, a YAML taskfile
+/// is rewritten line by line so that its comments and formatting are kept.
+/// When that doesn't produce the same result as migrating the document, or
+/// for other formats, the migrated document is written out instead.
+#[allow(clippy::redundant_field_names)]
+pub fn migrate(conf: &Config, path: &path::Path) -> Result<Migration> {
+  let data = match fs::read_to_string(path) {
+    Ok(data) => data,
+    Err(err) => return Err(error::Error::ReadError(path.to_owned(), err)),
+  };
+  let fmt = conf.format.or(Format::from_path(path)).unwrap_or(Format::Yaml);
+  if fmt.is_import() {
+    return Err(error::Error::UnsupportedError(format!("Cannot migrate a {:?} file; use 'psctl import' to create a taskfile from it", fmt)));
+  }
+
+  let doc: Value = fmt.parse(path, &data)?;
+  super::check_fields(conf, path, &doc)?;
+  let version = match super::check_version(path, &doc)? {
+    Some(version) => version,
+    None => return Err(error::Error::VersionError(path.to_owned(), "Taskfile has no version".to_string())),
+  };
+  if version == LATEST_VERSION {
+    return Ok(Migration{version: version, data: data, verbatim: true});
+  }
+
+  let doc = migrate_doc(doc);
+  if fmt == Format::Yaml {
+    let text = migrate_text(&data);
+    if let Ok(check) = fmt.parse::<Value>(path, &text) {
+      if check == doc {
+        return Ok(Migration{version: version, data: text, verbatim: true});
+      }
+    }
+  }
+
+  Ok(Migration{
+    version: version,
+    data: fmt.write(path, &doc)?,
+    verbatim: false,
+  })
+}
+
+// Version 2 describes checks as objects instead of URLs. Tasks are described
+// in the list of tasks, by templates, and by the overrides of profiles.
+fn migrate_doc(mut doc: Value) -> Value {
+  doc["version"] = LATEST_VERSION.into();
+  if let Some(tasks) = doc.get_mut("tasks").and_then(|e| e.as_sequence_mut()) {
+    tasks.iter_mut().for_each(migrate_task);
+  }
+  if let Some(templates) = doc.get_mut("templates").and_then(|e| e.as_mapping_mut()) {
+    templates.values_mut().for_each(migrate_task);
+  }
+  if let Some(profiles) = doc.get_mut("profiles").and_then(|e| e.as_mapping_mut()) {
+    for profile in profiles.values_mut() {
+      if let Some(tasks) = profile.get_mut("tasks").and_then(|e| e.as_mapping_mut()) {
+        tasks.values_mut().for_each(migrate_task);
+      }
+    }
+  }
+  doc
+}
+
+// Describe the checks of a task as objects.
+fn migrate_task(task: &mut Value) {
+  if let Some(checks) = task.get_mut("checks").and_then(|e| e.as_sequence_mut()) {
+    for check in checks.iter_mut() {
+      if check.is_string() {
+        let mut spec = Mapping::new();
+        spec.insert("url".into(), check.clone());
+        *check = Value::Mapping(spec);
+      }
+    }
+  }
+}
+
+// Migrate the text of a YAML taskfile. This handles taskfiles written in the
+// usual block style; anything more exotic is caught by comparing the result.
+fn migrate_text(data: &str) -> String {
+  let mut out: Vec<String> = Vec::new();
+  let mut checks: Option<usize> = None; // the indent of the checks we're in, if any
+  for line in data.lines() {
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+
+    if let Some(n) = checks {
+      if trimmed.is_empty() || trimmed.starts_with('#') {
+        out.push(line.to_owned());
+        continue;
+      }
+      if indent >= n && trimmed.starts_with("- ") {
+        out.push(format!("{}- url: {}", &line[..indent], trimmed[2..].trim_start()));
+        continue;
+      }
+      checks = None;
+    }
+
+    if indent == 0 && trimmed.starts_with("version:") {
+      let rest = &trimmed["version:".len()..];
+      if rest.split('#').next().unwrap_or_default().trim() == "1" {
+        out.push(format!("version:{}", rest.replacen('1', &LATEST_VERSION.to_string(), 1)));
+        continue;
+      }
+    }
+
+    // a key may be the first in a sequence item, like: '- checks:'
+    let (key, offset) = match trimmed.strip_prefix("- ") {
+      Some(key) => (key.trim_start(), indent + (trimmed.len() - key.trim_start().len())),
+      None => (trimmed, indent),
+    };
+    if let Some(rest) = key.strip_prefix("checks:") {
+      if rest.trim().is_empty() || rest.trim_start().starts_with('#') {
+        checks = Some(offset);
+      }
+    }
+    out.push(line.to_owned());
+  }
+
+  let mut text = out.join("\n");
+  if data.ends_with('\n') {
+    text.push('\n');
+  }
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_migrate_text() {
+    let data = r#"version: 1 # the first
+tasks:
+  -
+    name: a
+    run: sleep 3 && echo "A"
+    # wait for these
+    checks:
+      - shell:sleep 2 # or so
+
+      - "https://example.com/ready"
+    deps:
+      - b
+  - checks:
+    - file:///tmp/b.sock
+    run: b
+"#;
+    let expect = r#"version: 2 # the first
+tasks:
+  -
+    name: a
+    run: sleep 3 && echo "A"
+    # wait for these
+    checks:
+      - url: shell:sleep 2 # or so
+
+      - url: "https://example.com/ready"
+    deps:
+      - b
+  - checks:
+    - url: file:///tmp/b.sock
+    run: b
+"#;
+    let text = migrate_text(data);
+    assert_eq!(expect, text);
+    assert_eq!(migrate_doc(serde_yaml::from_str(data).unwrap()), serde_yaml::from_str::<Value>(&text).unwrap());
+  }
+
+  #[test]
+  fn test_migrate_templates_and_profiles() {
+    let data = r#"version: 1
+templates:
+  web:
+    checks:
+      - http://localhost:8080/
+tasks:
+  - name: api
+    extends: web
+profiles:
+  ci:
+    tasks:
+      api:
+        checks:
+          - file:///tmp/api.ready
+"#;
+    let expect = r#"version: 2
+templates:
+  web:
+    checks:
+      - url: http://localhost:8080/
+tasks:
+  - name: api
+    extends: web
+profiles:
+  ci:
+    tasks:
+      api:
+        checks:
+          - url: file:///tmp/api.ready
+"#;
+    let doc = migrate_doc(serde_yaml::from_str(data).unwrap());
+    assert_eq!(serde_yaml::from_str::<Value>(expect).unwrap(), doc);
+    assert_eq!(expect, migrate_text(data));
+  }
+
+}
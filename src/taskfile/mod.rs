@@ -0,0 +1,697 @@
+pub mod error;
+mod compose;
+mod migrate;
+mod procfile;
+mod profile;
+
+pub use migrate::migrate;
+
+use std::fs;
+use std::env;
+use std::ffi;
+use std::path;
+use std::result;
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use schemars::JsonSchema;
+use colored::Colorize;
+
+use crate::runner;
+use crate::config;
+use crate::schema;
+
+type Result<T> = result::Result<T, error::Error>;
+
+/// The taskfile versions that can be read, oldest first.
+const VERSIONS: [u32; 2] = [1, 2];
+/// The current taskfile version, which is the one new taskfiles are written in.
+pub const LATEST_VERSION: u32 = VERSIONS[VERSIONS.len() - 1];
+
+/// The names of taskfiles that are discovered automatically, in order of preference.
+const DEFAULT_NAMES: [&str; 3] = ["psctl.yaml", "psctl.yml", ".psctl.yaml"];
+
+/// A taskfile, which describes tasks for psctl to run.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(title = "psctl taskfile", deny_unknown_fields)]
+pub struct SpecFile {
+  /// The version of the taskfile format.
+  #[schemars(range(min = 1, max = 2))]
+  version: u32,
+  /// Other taskfiles whose tasks are included in this one.
+  #[serde(default)]
+  include: Vec<Include>,
+  /// The tasks to run.
+  #[serde(default)]
+  tasks: Vec<runner::Process>,
+  /// Templates, which tasks can extend to share their fields, by name.
+  #[serde(default)]
+  templates: BTreeMap<String, runner::Process>,
+  /// Profiles, which adjust tasks for a particular environment, by name.
+  #[serde(default)]
+  profiles: BTreeMap<String, profile::ProfileSpec>,
+  /// When the pod finishes: on the first task to exit, once every task has exited, or once the named tasks have exited, like '{wait-for: [test]}'.
+  #[serde(skip_serializing_if="Option::is_none")]
+  exit_policy: Option<runner::ExitPolicy>,
+  /// A command run before any task is started, in the taskfile's directory.
+  #[serde(skip_serializing_if="Option::is_none")]
+  setup: Option<runner::Hook>,
+  /// A command run once every task has stopped, in the taskfile's directory.
+  #[serde(skip_serializing_if="Option::is_none")]
+  teardown: Option<runner::Hook>,
+}
+
+impl SpecFile {
+  // Check that the taskfile only uses what its version provides for.
+  fn validate(&self, path: &path::Path) -> Result<()> {
+    let fail = |task: &runner::Process, msg: String| Err(error::Error::VersionError(path.to_owned(), format!("{}: {}", task.key(), msg)));
+    for task in self.tasks.iter().chain(self.templates.values()) {
+      if self.version < 2 {
+        if let Some(check) = task.checks().iter().find(|e| !e.is_compact()) {
+          return fail(task, format!("Checks are described as objects from version 2 (set 'version: 2' or describe the check as a URL): {}", check));
+        }
+        if task.restart().is_some() {
+          return fail(task, "Restart policies require version 2; use 'psctl migrate' to upgrade this taskfile".to_string());
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Options that control how taskfiles are read.
+pub struct Config {
+  /// The format of taskfiles which can't be determined from their path.
+  pub format: Option<Format>,
+  /// Whether unknown fields are reported as warnings instead of errors.
+  pub lenient: bool,
+  /// The profiles to apply to tasks, in order.
+  pub profiles: Vec<String>,
+}
+
+impl Config {
+  pub fn from_options(opts: &config::Options) -> Self {
+    Self{
+      format: opts.format,
+      lenient: opts.lenient,
+      profiles: match opts.profile.is_empty() {
+        true  => env::var("PSCTL_PROFILE").unwrap_or_default().split(',').map(|e| e.trim()).filter(|e| !e.is_empty()).map(|e| e.to_owned()).collect(),
+        false => opts.profile.clone(),
+      },
+    }
+  }
+}
+
+/// Determine the version of a taskfile document and make sure it's one that
+/// can be read. A missing version is left for the taskfile's schema to report.
+fn check_version(path: &path::Path, doc: &serde_yaml::Value) -> Result<Option<u32>> {
+  let version = match doc.get("version") {
+    Some(version) => version,
+    None => return Ok(None),
+  };
+  match version.as_u64() {
+    Some(v) if VERSIONS.iter().any(|e| *e as u64 == v) => Ok(Some(v as u32)),
+    _ => Err(error::Error::VersionError(path.to_owned(), format!("Unsupported taskfile version: {}; versions {} to {} are supported, you may need a newer version of psctl", serde_yaml::to_string(version).unwrap_or_default().trim(), VERSIONS[0], LATEST_VERSION))),
+  }
+}
+
+/// Reject fields a taskfile doesn't define, so that a misspelled one isn't
+/// silently ignored. With 'lenient', they're only reported.
+fn check_fields(conf: &Config, path: &path::Path, doc: &serde_yaml::Value) -> Result<()> {
+  let unknown: Vec<String> = schema::unknown_fields(&schema::taskfile(), doc).iter().map(|e| e.to_string()).collect();
+  if unknown.is_empty() {
+    Ok(())
+  }else if conf.lenient {
+    for e in unknown {
+      eprintln!("{}", &format!("* * * {}: {} (ignored)", path.display(), e).yellow());
+    }
+    Ok(())
+  }else{
+    Err(error::Error::UnknownFieldError(path.to_owned(), unknown))
+  }
+}
+
+/// An included taskfile is described by a path or glob, which is relative to
+/// the including taskfile, and optionally a namespace for its tasks.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(untagged)]
+#[schemars(deny_unknown_fields)]
+pub enum Include {
+  Path(String),
+  Spec {
+    /// A path or glob, relative to this taskfile.
+    path: String,
+    /// A namespace the included tasks are named within.
+    namespace: Option<String>,
+  },
+}
+
+impl Include {
+  fn path(&self) -> &str {
+    match self {
+      Self::Path(path) => path,
+      Self::Spec{path, ..} => path,
+    }
+  }
+
+  fn namespace(&self) -> Option<&str> {
+    match self {
+      Self::Path(_) => None,
+      Self::Spec{namespace, ..} => namespace.as_deref(),
+    }
+  }
+}
+
+/// The formats a taskfile can be written in. Procfiles and Compose files are
+/// not taskfiles, but they can be read as one.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Yaml,
+  Json,
+  Toml,
+  Procfile,
+  Compose,
+}
+
+impl Format {
+  /// Determine the format of a taskfile from its name or extension, if possible.
+  pub fn from_path(path: &path::Path) -> Option<Format> {
+    let name = path.file_name()?.to_str()?;
+    if name == "Procfile" || name.starts_with("Procfile.") {
+      return Some(Format::Procfile);
+    }
+    if ["compose", "docker-compose"].iter().any(|e| name.starts_with(&format!("{}.", e))) && (name.ends_with(".yaml") || name.ends_with(".yml")) {
+      return Some(Format::Compose);
+    }
+    match path.extension()?.to_str()? {
+      "yaml" | "yml" => Some(Format::Yaml),
+      "json"         => Some(Format::Json),
+      "toml"         => Some(Format::Toml),
+      _              => None,
+    }
+  }
+
+  /// Whether this format is imported from another tool.
+  pub fn is_import(&self) -> bool {
+    matches!(self, Self::Procfile | Self::Compose)
+  }
+
+  fn parse<T: DeserializeOwned>(&self, path: &path::Path, data: &str) -> Result<T> {
+    match self {
+      Self::Yaml => serde_yaml::from_str(data).map_err(|err| error::Error::YamlError(path.to_owned(), err)),
+      Self::Json => serde_json::from_str(data).map_err(|err| error::Error::JsonError(path.to_owned(), err)),
+      Self::Toml => toml::from_str(data).map_err(|err| error::Error::TomlError(path.to_owned(), err)),
+      Self::Procfile => serde_yaml::from_value(procfile::read(path, data)?).map_err(|err| error::Error::YamlError(path.to_owned(), err)),
+      Self::Compose => serde_yaml::from_value(compose::read(path, data)?.0).map_err(|err| error::Error::YamlError(path.to_owned(), err)),
+    }
+  }
+
+  // Parse a document, along with anything an imported file defines which
+  // couldn't be expressed in a taskfile and was left out.
+  fn parse_doc(&self, path: &path::Path, data: &str) -> Result<(serde_yaml::Value, Vec<String>)> {
+    match self {
+      Self::Compose => compose::read(path, data),
+      _ => Ok((self.parse(path, data)?, Vec::new())),
+    }
+  }
+
+  fn write<T: Serialize>(&self, path: &path::Path, val: &T) -> Result<String> {
+    match self {
+      Self::Yaml => serde_yaml::to_string(val).map_err(|err| error::Error::YamlError(path.to_owned(), err)),
+      Self::Json => serde_json::to_string_pretty(val).map(|e| e + "\n").map_err(|err| error::Error::JsonError(path.to_owned(), err)),
+      Self::Toml => toml::to_string(val).map_err(|err| error::Error::TomlWriteError(path.to_owned(), err)),
+      Self::Procfile | Self::Compose => Err(error::Error::UnsupportedError(format!("Cannot write a taskfile as: {:?}", self))),
+    }
+  }
+}
+
+/// Determine which taskfiles should be read. Taskfiles provided as arguments
+/// are preferred. Otherwise, if no tasks were specified on the command line,
+/// the one named by the environment, as 'PSCTL_FILE', is used, or a default
+/// taskfile is looked for in the working directory and its parents.
+pub fn resolve(opts: &config::Options, env: Option<ffi::OsString>) -> Result<Vec<path::PathBuf>> {
+  if !opts.file.is_empty() {
+    return Ok(opts.file.iter().map(path::PathBuf::from).collect());
+  }
+  if !opts.specs.is_empty() {
+    return Ok(Vec::new());
+  }
+  if let Some(file) = env {
+    if !file.is_empty() {
+      return Ok(vec![path::PathBuf::from(file)]);
+    }
+  }
+  Ok(discover(&env::current_dir()?).into_iter().collect())
+}
+
+/// The file in which the process ID of psctl is recorded while it runs the
+/// provided taskfiles, so that it can be found by 'psctl reload'. The file is
+/// kept in the user's runtime directory, and its name is derived from the
+/// taskfiles' absolute paths.
+pub fn pidfile(paths: &[path::PathBuf]) -> Result<path::PathBuf> {
+  use std::os::unix::ffi::OsStrExt;
+  // FNV-1a, which unlike the standard library's hashers is the same from one
+  // release to the next.
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for path in paths {
+    for byte in fs::canonicalize(path)?.as_os_str().as_bytes().iter().chain(&[0]) {
+      hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+    }
+  }
+  Ok(runtime_dir()?.join(format!("psctl-{:016x}.pid", hash)))
+}
+
+// The directory for the user's runtime files: '$XDG_RUNTIME_DIR', or else a
+// directory of psctl's own in the temporary directory, which only the user
+// can access.
+fn runtime_dir() -> Result<path::PathBuf> {
+  use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+  if let Some(dir) = env::var_os("XDG_RUNTIME_DIR").filter(|e| !e.is_empty()) {
+    return Ok(path::PathBuf::from(dir));
+  }
+  let uid = nix::unistd::geteuid().as_raw();
+  let dir = env::temp_dir().join(format!("psctl-{}", uid));
+  if let Err(err) = fs::DirBuilder::new().mode(0o700).create(&dir) {
+    if err.kind() != std::io::ErrorKind::AlreadyExists {
+      return Err(err.into());
+    }
+  }
+  let meta = fs::symlink_metadata(&dir)?;
+  if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+    return Err(error::Error::UnsupportedError(format!("Runtime directory is not private to this user: {}", dir.display())));
+  }
+  Ok(dir)
+}
+
+fn discover(dir: &path::Path) -> Option<path::PathBuf> {
+  for dir in dir.ancestors() {
+    for name in DEFAULT_NAMES {
+      let file = dir.join(name);
+      if file.is_file() {
+        return Some(file);
+      }
+    }
+  }
+  None
+}
+
+/// A task as it was read from a taskfile, along with the fields that were
+/// explicitly set for it, which are the ones it overrides when it is merged.
+#[derive(Clone)]
+struct Task {
+  proc: runner::Process,
+  fields: Vec<String>,
+}
+
+/// The tasks and profiles defined by a taskfile and everything it includes,
+/// along with the exit policy and hooks the taskfile itself sets.
+struct Contents {
+  tasks: Vec<Task>,
+  profiles: Vec<profile::Profile>,
+  exit_policy: Option<runner::ExitPolicy>,
+  setup: Option<runner::Hook>,
+  teardown: Option<runner::Hook>,
+  omitted: Vec<String>,
+}
+
+/// The tasks and profiles read from taskfiles.
+pub struct Taskfile {
+  procs: Vec<runner::Process>,
+  profiles: Vec<profile::Profile>,
+  exit_policy: Option<runner::ExitPolicy>,
+  setup: Option<runner::Hook>,
+  teardown: Option<runner::Hook>,
+  omitted: Vec<String>,
+}
+
+impl Taskfile {
+  /// The exit policy, which is set by the last taskfile that sets one.
+  pub fn exit_policy(&self) -> runner::ExitPolicy {
+    self.exit_policy.clone().unwrap_or_default()
+  }
+
+  /// The hook run before any task is started, which is set by the last
+  /// taskfile that sets one.
+  pub fn setup(&self) -> Option<runner::Hook> {
+    self.setup.clone()
+  }
+
+  /// The hook run once every task has stopped, which is set by the last
+  /// taskfile that sets one.
+  pub fn teardown(&self) -> Option<runner::Hook> {
+    self.teardown.clone()
+  }
+
+  /// What imported files define which couldn't be expressed as tasks and
+  /// was left out, like Compose services that have no command.
+  pub fn omitted(&self) -> &[String] {
+    &self.omitted
+  }
+
+  /// The names of the profiles that are defined.
+  pub fn profiles(&self) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for e in &self.profiles {
+      if !names.contains(&e.name.as_str()) {
+        names.push(&e.name);
+      }
+    }
+    names
+  }
+
+  /// The tasks to run with profiles applied, in order. A profile may be
+  /// defined in more than one taskfile, in which case every definition is
+  /// applied. Tasks which are disabled are omitted.
+  pub fn procs(&self, profiles: &[String]) -> Result<Vec<runner::Process>> {
+    let mut procs = self.procs.clone();
+    for name in profiles {
+      let mut found = false;
+      for e in self.profiles.iter().filter(|e| &e.name == name) {
+        e.apply(&mut procs)?;
+        found = true;
+      }
+      if !found {
+        return Err(error::Error::ProfileError(format!("Profile is not defined: {}; defined profiles are: {}", name, self.profiles().join(", "))));
+      }
+    }
+    for proc in procs.iter().filter(|e| e.enabled()) {
+      if let Some(dep) = proc.deps().iter().find(|d| procs.iter().any(|e| e.key() == *d && !e.enabled())) {
+        return Err(error::Error::DisabledError(proc.key().to_owned(), dep.to_owned()));
+      }
+    }
+    Ok(procs.into_iter().filter(|e| e.enabled()).collect())
+  }
+}
+
+/// Read the tasks and profiles defined by taskfiles and everything they
+/// include. When more than one taskfile defines the same task, the later
+/// definition is merged into the earlier one.
+#[allow(clippy::redundant_field_names)]
+pub fn read<P: AsRef<path::Path>>(conf: &Config, paths: &[P]) -> Result<Taskfile> {
+  let mut procs: Vec<runner::Process> = Vec::new();
+  let mut profiles: Vec<profile::Profile> = Vec::new();
+  let mut exit_policy: Option<runner::ExitPolicy> = None;
+  let mut setup: Option<runner::Hook> = None;
+  let mut teardown: Option<runner::Hook> = None;
+  let mut omitted: Vec<String> = Vec::new();
+  for path in paths {
+    let contents = read_tasks(conf, path.as_ref())?;
+    if contents.exit_policy.is_some() {
+      exit_policy = contents.exit_policy;
+    }
+    if contents.setup.is_some() {
+      setup = contents.setup;
+    }
+    if contents.teardown.is_some() {
+      teardown = contents.teardown;
+    }
+    for task in contents.tasks {
+      match procs.iter_mut().find(|e| e.key() == task.proc.key()) {
+        Some(base) => *base = base.overlay(&task.proc, &task.fields),
+        None => procs.push(task.proc),
+      }
+    }
+    profiles.extend(contents.profiles);
+    omitted.extend(contents.omitted);
+  }
+  Ok(Taskfile{
+    procs: procs,
+    profiles: profiles,
+    exit_policy: exit_policy,
+    setup: setup,
+    teardown: teardown,
+    omitted: omitted,
+  })
+}
+
+fn read_tasks(conf: &Config, path: &path::Path) -> Result<Contents> {
+  let contents = read_file(conf, path, &mut Vec::new())?;
+  let mut keys: HashSet<&str> = HashSet::new();
+  for task in &contents.tasks {
+    if !keys.insert(task.proc.key()) {
+      return Err(error::Error::DuplicateTaskError(task.proc.key().to_owned()));
+    }
+  }
+  Ok(contents)
+}
+
+/// The result of converting a taskfile.
+pub struct Conversion {
+  /// The converted taskfile.
+  pub data: String,
+  /// What an imported file defines which couldn't be expressed as tasks and
+  /// was left out.
+  pub omitted: Vec<String>,
+}
+
+/// Convert a taskfile to another format. The document is converted as it is
+/// written; includes are not expanded. Procfiles and Compose files are
+/// converted to the equivalent taskfile.
+pub fn convert(conf: &Config, path: &path::Path, to: Format) -> Result<Conversion> {
+  let data = match fs::read_to_string(path) {
+    Ok(data) => data,
+    Err(err) => return Err(error::Error::ReadError(path.to_owned(), err)),
+  };
+  let format = conf.format.or(Format::from_path(path)).unwrap_or(Format::Yaml);
+  let (doc, omitted) = format.parse_doc(path, &data)?;
+  check_fields(conf, path, &doc)?;
+  Ok(Conversion{
+    data: to.write(path, &doc)?,
+    omitted,
+  })
+}
+
+// A taskfile's format is the one provided, if any, otherwise the one its
+// extension identifies, otherwise YAML. The taskfiles it includes are
+// identified by their extension first, since they needn't share its format.
+#[allow(clippy::redundant_field_names)]
+fn read_file(conf: &Config, path: &path::Path, stack: &mut Vec<path::PathBuf>) -> Result<Contents> {
+  let canonical = match fs::canonicalize(path) {
+    Ok(canonical) => canonical,
+    Err(err) => return Err(error::Error::ReadError(path.to_owned(), err)),
+  };
+  if stack.contains(&canonical) {
+    return Err(error::Error::IncludeError(format!("Taskfile includes itself: {}", stack.iter().chain([&canonical]).map(|e| e.display().to_string()).collect::<Vec<String>>().join(" → "))));
+  }
+
+  let data = match fs::read_to_string(path) {
+    Ok(data) => data,
+    Err(err) => return Err(error::Error::ReadError(path.to_owned(), err)),
+  };
+  let fmt = match stack.is_empty() {
+    true  => conf.format.or(Format::from_path(path)),
+    false => Format::from_path(path).or(conf.format),
+  };
+  let fmt = fmt.unwrap_or(Format::Yaml);
+  let (doc, omitted) = fmt.parse_doc(path, &data)?;
+  check_version(path, &doc)?;
+  check_fields(conf, path, &doc)?;
+  let spec: SpecFile = match fmt.is_import() {
+    true  => serde_yaml::from_value(doc.clone()).map_err(|err| error::Error::YamlError(path.to_owned(), err))?,
+    false => fmt.parse(path, &data)?, // parse the source again to report errors with their location
+  };
+  spec.validate(path)?;
+
+  let origin = runner::Origin::new(path);
+  let mut tasks: Vec<Task> = Vec::new();
+  for (i, e) in spec.tasks.iter().enumerate() {
+    let task = Task{
+      proc: e.with_origin(origin.clone()),
+      fields: fields(&doc["tasks"][i]),
+    };
+    tasks.push(match e.extends() {
+      Some(name) => extend(path, &origin, &spec, &doc, name, task)?,
+      None => task,
+    });
+  }
+  let mut omitted: Vec<String> = omitted.into_iter().map(|e| format!("{}: {}", path.display(), e)).collect();
+  let mut profiles: Vec<profile::Profile> = spec.profiles.iter().map(|(name, e)| profile::Profile::new(name, e, &doc["profiles"][name.as_str()])).collect();
+
+  stack.push(canonical);
+  for inc in &spec.include {
+    for file in expand_include(path, inc.path())? {
+      let sub = read_file(conf, &file, stack)?;
+      let sub = match inc.namespace() {
+        Some(ns) => with_namespace(ns, sub),
+        None => sub,
+      };
+      tasks.extend(sub.tasks); // the exit policy and hooks of an included taskfile don't apply
+      profiles.extend(sub.profiles);
+      omitted.extend(sub.omitted);
+    }
+  }
+  stack.pop();
+
+  Ok(Contents{
+    tasks: tasks,
+    profiles: profiles,
+    exit_policy: spec.exit_policy,
+    setup: spec.setup.map(|e| e.with_origin(origin.clone())),
+    teardown: spec.teardown.map(|e| e.with_origin(origin.clone())),
+    omitted: omitted,
+  })
+}
+
+// Base a task on a template defined in the same taskfile. The task's fields
+// are merged into the template's, as though the task overrides it.
+#[allow(clippy::redundant_field_names)]
+fn extend(path: &path::Path, origin: &runner::Origin, spec: &SpecFile, doc: &serde_yaml::Value, name: &str, task: Task) -> Result<Task> {
+  let base = match spec.templates.get(name) {
+    Some(base) => base,
+    None => return Err(error::Error::TemplateError(path.to_owned(), format!("{}: Template is not defined: {}", task.proc.key(), name))),
+  };
+  if base.extends().is_some() {
+    return Err(error::Error::TemplateError(path.to_owned(), format!("Template cannot extend another template: {}", name)));
+  }
+  let proc = base.with_origin(origin.clone()).overlay(&task.proc, &task.fields);
+  let mut fields: Vec<String> = fields(&doc["templates"][name]).into_iter().filter(|e| e != "name").collect();
+  for field in task.fields {
+    if !fields.contains(&field) {
+      fields.push(field);
+    }
+  }
+  Ok(Task{
+    proc: proc,
+    fields: fields,
+  })
+}
+
+// The fields that are set in a task definition.
+fn fields(def: &serde_yaml::Value) -> Vec<String> {
+  match def.as_mapping() {
+    Some(def) => def.keys().filter_map(|e| e.as_str()).map(|e| e.to_owned()).collect(),
+    None => Vec::new(),
+  }
+}
+
+// Resolve an include, which may be a glob, relative to the including file.
+// A glob that matches nothing is fine, but a literal path must exist.
+fn expand_include(base: &path::Path, include: &str) -> Result<Vec<path::PathBuf>> {
+  let pattern = match base.parent() {
+    Some(dir) => dir.join(include),
+    None => path::PathBuf::from(include),
+  };
+  let pattern = pattern.to_string_lossy();
+  if glob::Pattern::escape(&pattern) == pattern {
+    return Ok(vec![path::PathBuf::from(pattern.as_ref())]);
+  }
+  let mut files: Vec<path::PathBuf> = Vec::new();
+  for file in glob::glob(&pattern)? {
+    match file {
+      Ok(file) => files.push(file),
+      Err(err) => return Err(error::Error::ReadError(err.path().to_owned(), err.into())),
+    }
+  }
+  files.sort();
+  Ok(files)
+}
+
+// Place tasks in a namespace. Dependencies on tasks defined alongside them
+// are namespaced too, as are the tasks profiles refer to; any others refer
+// to tasks defined elsewhere.
+fn with_namespace(ns: &str, contents: Contents) -> Contents {
+  let local: HashSet<String> = contents.tasks.iter().map(|e| e.proc.key().to_owned()).collect();
+  Contents{
+    tasks: contents.tasks.into_iter().map(|e| Task{
+      proc: e.proc.with_namespace(ns, &local),
+      fields: e.fields,
+    }).collect(),
+    profiles: contents.profiles.iter().map(|e| e.with_namespace(ns, &local)).collect(),
+    exit_policy: contents.exit_policy,
+    setup: contents.setup,
+    teardown: contents.teardown,
+    omitted: contents.omitted,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_discover() {
+    let base = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+    assert_eq!(Some(base.join("discover").join("psctl.yaml")), discover(&base.join("discover").join("nested").join("dir")));
+  }
+
+  #[test]
+  fn test_resolve() {
+    use clap::Parser;
+    let env = || Some(ffi::OsString::from("/srv/app/psctl.yaml"));
+    assert_eq!(vec![path::PathBuf::from("/srv/app/psctl.yaml")], resolve(&config::Options::parse_from(["psctl"]), env()).unwrap());
+    assert_eq!(vec![path::PathBuf::from("local.yaml")], resolve(&config::Options::parse_from(["psctl", "-f", "local.yaml"]), env()).unwrap());
+    assert!(resolve(&config::Options::parse_from(["psctl", "a: echo A"]), env()).unwrap().is_empty()); // specifiers are run on their own
+  }
+
+  #[test]
+  fn test_formats() {
+    let conf = Config{format: None, lenient: false, profiles: vec![]};
+    let base = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+    let yaml = read(&conf, &[base.join("env.yaml")]).unwrap().procs(&[]).unwrap();
+    let toml = read(&conf, &[base.join("env.toml")]).unwrap().procs(&[]).unwrap();
+    assert_eq!(serde_yaml::to_string(&yaml).unwrap(), serde_yaml::to_string(&toml).unwrap()); // they differ only in their origin
+
+    let toml = convert(&conf, &base.join("env.yaml"), Format::Toml).unwrap().data;
+    let doc: serde_yaml::Value = Format::Toml.parse(path::Path::new("env.toml"), &toml).unwrap();
+    assert_eq!(Format::Yaml.parse::<serde_yaml::Value>(path::Path::new("env.yaml"), &fs::read_to_string(base.join("env.yaml")).unwrap()).unwrap(), doc);
+    assert_eq!(toml, Format::Toml.write(path::Path::new("env.toml"), &doc).unwrap());
+
+    assert!(convert(&Config{format: Some(Format::Json), lenient: false, profiles: vec![]}, &base.join("env.yaml"), Format::Yaml).is_err()); // the format provided takes precedence over the extension
+  }
+
+  #[test]
+  fn test_unknown_fields() {
+    let path = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test").join("unknown.yaml");
+    let conf = Config{format: None, lenient: false, profiles: vec![]};
+    assert!(matches!(read(&conf, &[&path]), Err(error::Error::UnknownFieldError(_, _))));
+    assert!(matches!(convert(&conf, &path, Format::Json), Err(error::Error::UnknownFieldError(_, _))));
+    assert!(matches!(migrate(&conf, &path), Err(error::Error::UnknownFieldError(_, _))));
+    let conf = Config{format: None, lenient: true, profiles: vec![]};
+    assert!(convert(&conf, &path, Format::Json).is_ok());
+  }
+
+  #[test]
+  fn test_namespace() {
+    let tasks = vec![
+      Task{proc: runner::Process::new(Some("db"), "db", vec![], None), fields: vec![]},
+      Task{proc: runner::Process::new(Some("api"), "api", vec!["db", "auth.api"], None), fields: vec![]},
+    ];
+    let tasks = with_namespace("billing", Contents{tasks, profiles: vec![], exit_policy: None, setup: None, teardown: None, omitted: vec![]}).tasks;
+    assert_eq!(Some("billing.db"), tasks[0].proc.label());
+    assert_eq!(Some("billing.api"), tasks[1].proc.label());
+    assert_eq!(&vec!["billing.db".to_string(), "auth.api".to_string()], tasks[1].proc.deps());
+  }
+
+  #[test]
+  fn test_profiles() {
+    let conf = Config{format: None, lenient: false, profiles: vec![]};
+    let file = read(&conf, &[path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test").join("profiles.yaml")]).unwrap();
+    assert_eq!(vec!["ci", "demo"], file.profiles());
+
+    let procs = file.procs(&[]).unwrap();
+    assert_eq!(vec!["db", "api"], procs.iter().map(|e| e.key()).collect::<Vec<&str>>());
+
+    let procs = file.procs(&["ci".to_string()]).unwrap();
+    assert_eq!(vec!["db", "api", "test"], procs.iter().map(|e| e.key()).collect::<Vec<&str>>());
+    assert_eq!("./api --log-format json", procs[1].command());
+    assert_eq!(&vec!["db".to_string()], procs[1].deps()); // fields that are not overridden are kept
+
+    assert!(matches!(file.procs(&["demo".to_string()]), Err(error::Error::DisabledError(_, _))));
+    assert!(matches!(file.procs(&["staging".to_string()]), Err(error::Error::ProfileError(_))));
+  }
+
+  #[test]
+  fn test_templates() {
+    let conf = Config{format: None, lenient: false, profiles: vec![]};
+    let procs = read(&conf, &[path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test").join("templates.yaml")]).unwrap().procs(&conf.profiles).unwrap();
+    assert_eq!(vec!["worker", "mailer"], procs.iter().map(|e| e.key()).collect::<Vec<&str>>());
+    assert_eq!("./bin/worker", procs[0].command());
+    assert_eq!(&vec!["queue".to_string()], procs[0].deps());
+    assert_eq!("./bin/mailer", procs[1].command());
+    assert_eq!(&vec!["queue".to_string()], procs[1].deps());
+    assert_eq!(1, procs[1].checks().len());
+    assert_eq!(&vec![crate::waiter::Check::new("shell:pgrep -f worker")], procs[0].checks());
+  }
+
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::path;
+
+use serde_yaml::{Mapping, Value};
+
+use super::error;
+use super::Result;
+
+/// Read a Heroku/Foreman-style Procfile into a taskfile document. Each line
+/// of a Procfile defines a process as '<name>: <command>'. If a '.env' file
+/// exists alongside the Procfile, its variables are provided to every task.
+pub fn read(path: &path::Path, data: &str) -> Result<Value> {
+  let env = match path.parent() {
+    Some(dir) => read_env(&dir.join(".env"))?,
+    None => Mapping::new(),
+  };
+
+  let mut tasks: Vec<Value> = Vec::new();
+  for (i, line) in data.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let (name, cmd) = match line.split_once(':') {
+      Some((name, cmd)) => (name.trim(), cmd.trim()),
+      None => return Err(error::Error::ImportError(path.to_owned(), format!("Invalid process definition on line {}: {}", i + 1, line))),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+      return Err(error::Error::ImportError(path.to_owned(), format!("Invalid process name on line {}: {}", i + 1, name)));
+    }
+    let mut task = Mapping::new();
+    task.insert("name".into(), name.into());
+    task.insert("run".into(), cmd.into());
+    if !env.is_empty() {
+      task.insert("env".into(), Value::Mapping(env.clone()));
+    }
+    tasks.push(Value::Mapping(task));
+  }
+
+  let mut doc = Mapping::new();
+  doc.insert("version".into(), super::LATEST_VERSION.into());
+  doc.insert("tasks".into(), Value::Sequence(tasks));
+  Ok(Value::Mapping(doc))
+}
+
+// Read variables from a dotenv file, if it exists. Lines are in the form
+// '[export] KEY=VALUE', where the value may be quoted.
+fn read_env(path: &path::Path) -> Result<Mapping> {
+  let data = match fs::read_to_string(path) {
+    Ok(data) => data,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Mapping::new()),
+    Err(err) => return Err(error::Error::ReadError(path.to_owned(), err)),
+  };
+  let mut env = Mapping::new();
+  for (i, line) in data.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, val) = match line.split_once('=') {
+      Some((key, val)) => (key.trim(), val.trim()),
+      None => return Err(error::Error::ImportError(path.to_owned(), format!("Invalid variable on line {}: {}", i + 1, line))),
+    };
+    let val = match (val.chars().next(), val.chars().last()) {
+      (Some('"'), Some('"')) | (Some('\''), Some('\'')) if val.len() > 1 => &val[1..val.len() - 1],
+      _ => val,
+    };
+    env.insert(key.into(), val.into());
+  }
+  Ok(env)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_read() {
+    let doc = read(path::Path::new("Procfile"), "# processes\nweb: bundle exec rails s -p $PORT\n\nworker:bin/worker --queue=default\n").unwrap();
+    let tasks = doc["tasks"].as_sequence().unwrap();
+    assert_eq!(2, tasks.len());
+    assert_eq!(tasks[0]["name"], "web");
+    assert_eq!(tasks[0]["run"], "bundle exec rails s -p $PORT");
+    assert_eq!(tasks[1]["name"], "worker");
+    assert_eq!(tasks[1]["run"], "bin/worker --queue=default");
+
+    assert!(read(path::Path::new("Procfile"), "web bundle exec rails s\n").is_err());
+  }
+
+}
@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use crate::runner;
+
+use super::error;
+use super::Result;
+use super::Task;
+
+/// A profile adjusts the tasks in a taskfile for a particular environment,
+/// like continuous integration or a demo.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[schemars(deny_unknown_fields)]
+pub struct ProfileSpec {
+  /// Environment variables to set for every task.
+  #[serde(default)]
+  env: HashMap<String, String>,
+  /// Tasks to run which are otherwise disabled.
+  #[serde(default)]
+  enable: Vec<String>,
+  /// Tasks not to run.
+  #[serde(default)]
+  disable: Vec<String>,
+  /// Fields to override in tasks, by the name of the task.
+  #[serde(default)]
+  tasks: BTreeMap<String, runner::Process>,
+}
+
+/// A profile as it was read from a taskfile.
+#[derive(Clone)]
+pub struct Profile {
+  pub name: String,
+  env: HashMap<String, String>,
+  enable: Vec<String>,
+  disable: Vec<String>,
+  tasks: Vec<(String, Task)>,
+}
+
+impl Profile {
+  /// Create a profile from its definition. The fields that are set for each
+  /// task it overrides are taken from the profile's document.
+  pub fn new(name: &str, spec: &ProfileSpec, doc: &serde_yaml::Value) -> Self {
+    Self{
+      name: name.to_owned(),
+      env: spec.env.clone(),
+      enable: spec.enable.clone(),
+      disable: spec.disable.clone(),
+      tasks: spec.tasks.iter().map(|(key, proc)| (key.to_owned(), Task{
+        proc: proc.clone(),
+        fields: super::fields(&doc["tasks"][key.as_str()]).into_iter().filter(|e| e != "name").collect(),
+      })).collect(),
+    }
+  }
+
+  /// Place the tasks a profile refers to in a namespace, when they are
+  /// defined alongside it.
+  pub fn with_namespace(&self, ns: &str, local: &HashSet<String>) -> Self {
+    let name = |e: &String| match local.contains(e) {
+      true  => format!("{}.{}", ns, e),
+      false => e.to_owned(),
+    };
+    Self{
+      name: self.name.clone(),
+      env: self.env.clone(),
+      enable: self.enable.iter().map(name).collect(),
+      disable: self.disable.iter().map(name).collect(),
+      tasks: self.tasks.iter().map(|(key, task)| (name(key), task.clone())).collect(),
+    }
+  }
+
+  /// Apply a profile to tasks. Tasks are enabled and disabled first, then the
+  /// profile's environment is set, and finally fields are overridden.
+  pub fn apply(&self, procs: &mut [runner::Process]) -> Result<()> {
+    let fail = |key: &str| Err(error::Error::ProfileError(format!("Profile '{}' refers to an undefined task: {}", self.name, key)));
+    for (keys, enabled) in [(&self.enable, true), (&self.disable, false)] {
+      for key in keys {
+        match procs.iter_mut().find(|e| e.key() == key) {
+          Some(proc) => *proc = proc.with_enabled(enabled),
+          None => return fail(key),
+        }
+      }
+    }
+    if !self.env.is_empty() {
+      for proc in procs.iter_mut() {
+        *proc = proc.with_env(&self.env);
+      }
+    }
+    for (key, task) in &self.tasks {
+      match procs.iter_mut().find(|e| e.key() == key) {
+        Some(proc) => *proc = proc.overlay(&task.proc, &task.fields),
+        None => return fail(key),
+      }
+    }
+    Ok(())
+  }
+}
@@ -24,6 +24,7 @@ impl fmt::Display for AwaitError {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
   IOError(io::Error),
   AwaitError(AwaitError),
@@ -0,0 +1,153 @@
+use std::fs;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// The port a 'listen' check waits for, from what follows its scheme: a port
+/// number, or 'any' for any port, which is described as none.
+pub fn parse_port(text: &str) -> Option<Option<u16>> {
+  match text {
+    "any" => Some(None),
+    text => match text.parse::<u16>() {
+      Ok(port) if port > 0 => Some(Some(port)),
+      _ => None,
+    },
+  }
+}
+
+/// Whether any process of a task is listening for TCP connections on a
+/// port, or on any port when none is provided. The task's processes are
+/// those descended from it, along with those left in its process group when
+/// their parent exits. The sockets that are listening are read from
+/// '/proc/net', and matched to the processes which hold them by their inodes.
+pub fn listening(pid: i32, port: Option<u16>) -> bool {
+  let socks = listeners(port);
+  if socks.is_empty() {
+    return false;
+  }
+  members(pid).into_iter().any(|pid| {
+    match fs::read_dir(format!("/proc/{}/fd", pid)) {
+      Ok(dir) => dir.flatten().filter_map(|e| fs::read_link(e.path()).ok()).any(|e| parse_socket(&e.to_string_lossy()).is_some_and(|e| socks.contains(&e))),
+      Err(_) => false, // the process has exited
+    }
+  })
+}
+
+// The inodes of TCP sockets which are listening on a port, or on any port.
+fn listeners(port: Option<u16>) -> HashSet<u64> {
+  let mut res: HashSet<u64> = HashSet::new();
+  for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+    for line in fs::read_to_string(table).unwrap_or_default().lines().skip(1) { // the first line names the columns
+      if let Some((local, inode)) = parse_listener(line) {
+        if port.is_none() || port == Some(local) {
+          res.insert(inode);
+        }
+      }
+    }
+  }
+  res
+}
+
+// The processes of a task: the task, which leads its process group, and
+// every process descended from it or in its process group.
+fn members(pid: i32) -> Vec<i32> {
+  let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+  let mut res: Vec<i32> = vec![pid];
+  if let Ok(dir) = fs::read_dir("/proc") {
+    for ent in dir.flatten() {
+      let child: i32 = match ent.file_name().to_string_lossy().parse() {
+        Ok(child) => child,
+        Err(_) => continue,
+      };
+      if let Some((ppid, pgrp)) = fs::read_to_string(ent.path().join("stat")).ok().and_then(|e| parse_stat(&e)) {
+        match pgrp == pid && child != pid {
+          true  => res.push(child),
+          false => children.entry(ppid).or_default().push(child),
+        }
+      }
+    }
+  }
+  let mut next: Vec<i32> = res.clone();
+  while let Some(pid) = next.pop() {
+    for child in children.remove(&pid).unwrap_or_default() {
+      res.push(child);
+      next.push(child);
+    }
+  }
+  res
+}
+
+// The local port and inode of a socket which is listening, from a line of
+// '/proc/net/tcp' or '/proc/net/tcp6'.
+fn parse_listener(line: &str) -> Option<(u16, u64)> {
+  const LISTEN: &str = "0A";
+  let fields: Vec<&str> = line.split_whitespace().collect();
+  if fields.len() < 10 || fields[3] != LISTEN {
+    return None;
+  }
+  let (_, port) = fields[1].rsplit_once(':')?;
+  let port = u16::from_str_radix(port, 16).ok()?;
+  let inode = fields[9].parse().ok()?;
+  Some((port, inode))
+}
+
+// The inode of a socket, from the target of a file descriptor's link.
+fn parse_socket(link: &str) -> Option<u64> {
+  link.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+}
+
+// The parent and process group of a process, from its '/proc/<pid>/stat'.
+// The command name is in parentheses and may contain anything, so fields
+// are read from after the last parenthesis.
+fn parse_stat(stat: &str) -> Option<(i32, i32)> {
+  let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(1);
+  let ppid = fields.next()?.parse().ok()?;
+  let pgrp = fields.next()?.parse().ok()?;
+  Some((ppid, pgrp))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_listen() {
+    assert_eq!(Some(Some(8080)), parse_port("8080"));
+    assert_eq!(Some(None), parse_port("any"));
+    assert_eq!(None, parse_port("0"));
+    assert_eq!(None, parse_port("http"));
+
+    assert_eq!(Some((8080, 41877)), parse_listener("   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 41877 1 0000000000000000 100 0 0 10 0"));
+    assert_eq!(Some((443, 9)), parse_listener("   1: 00000000000000000000000000000000:01BB 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 9 1 0000000000000000 100 0 0 10 0"));
+    assert_eq!(None, parse_listener("   2: 0100007F:1F90 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000  1000        0 41900 1 0000000000000000 20 4 30 10 -1"));
+
+    assert_eq!(Some(41877), parse_socket("socket:[41877]"));
+    assert_eq!(None, parse_socket("pipe:[41877]"));
+    assert_eq!(Some((1, 42)), parse_stat("43 (a) b) S 1 42 42 0 -1"));
+  }
+
+  #[test]
+  fn test_members() {
+    use std::os::unix::process::CommandExt;
+    let mut task = std::process::Command::new("sh").arg("-c").arg("setsid sleep 5 & wait").process_group(0).spawn().unwrap();
+    let pid = task.id() as i32;
+    // the descendant that left the task's process group is found once it has
+    // started, which takes a moment
+    let pgrp = |pid: &i32| fs::read_to_string(format!("/proc/{}/stat", pid)).ok().and_then(|e| parse_stat(&e)).map(|(_, pgrp)| pgrp);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let (members, found) = loop {
+      let members = members(pid);
+      let found = members.iter().any(|e| pgrp(e).is_some_and(|e| e != pid));
+      if found || std::time::Instant::now() > deadline {
+        break (members, found);
+      }
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    for pid in &members {
+      let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(*pid), nix::sys::signal::Signal::SIGKILL);
+    }
+    task.wait().unwrap();
+    assert!(members.contains(&pid));
+    assert!(found, "the descendant that called setsid is a member");
+  }
+
+}
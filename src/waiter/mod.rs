@@ -1,24 +1,33 @@
 pub mod error;
+mod listen;
 
 use core::time;
+use std::fs;
+use std::fmt;
+use std::io;
 use std::path;
 use std::pin::Pin;
 use std::time::SystemTime;
 use std::result;
 
 use futures::Future;
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
 use colored::Colorize;
 use futures::future::try_join_all;
 use tokio::time::sleep;
 use humantime::format_duration;
 
 use crate::config;
+use crate::schema;
 
 type Result<T> = result::Result<T, error::Error>;
 
 pub struct Config {
   pub key: Option<String>,
   pub verbose: bool,
+  pub started: SystemTime,
+  pub pid: Option<u32>,
 }
 
 impl Config {
@@ -26,44 +35,253 @@ impl Config {
     Self{
       key: Some(key),
       verbose: opts.verbose(),
+      started: SystemTime::now(),
+      pid: None,
+    }
+  }
+
+  /// Check against a task that was started at a particular time.
+  pub fn with_started(mut self, started: SystemTime) -> Self {
+    self.started = started;
+    self
+  }
+
+  /// Check against a task's process, which leads its process group.
+  pub fn with_pid(mut self, pid: Option<u32>) -> Self {
+    self.pid = pid;
+    self
+  }
+}
+
+fn interval_default() -> time::Duration {
+  time::Duration::from_secs(1)
+}
+
+/// An availability check. A check is described by a URL, which is all there
+/// is to it in a version 1 taskfile; from version 2, a check can also be an
+/// object which provides options.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Check {
+  url: String,
+  interval: time::Duration,
+  compact: bool,
+  fresh: bool,
+  non_empty: bool,
+  contains: Option<String>,
+  socket: bool,
+  remove: bool,
+}
+
+/// An availability check. Requires version 2.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct CheckSpec {
+  /// The URL to check.
+  #[schemars(schema_with = "schema::check_url")]
+  url: String,
+  /// How often to attempt the check.
+  #[serde(with = "humantime_serde", default="interval_default")]
+  #[schemars(schema_with = "schema::duration")]
+  interval: time::Duration,
+  /// For 'file' checks, whether the file must have been created or modified since the task started.
+  #[serde(default, skip_serializing_if="is_false")]
+  fresh: bool,
+  /// For 'file' checks, whether the file must not be empty.
+  #[serde(default, skip_serializing_if="is_false")]
+  non_empty: bool,
+  /// For 'file' checks, a pattern the file's contents must match.
+  #[serde(skip_serializing_if="Option::is_none")]
+  contains: Option<String>,
+  /// For 'file' checks, whether the file must be a socket which accepts connections.
+  #[serde(default, skip_serializing_if="is_false")]
+  socket: bool,
+  /// For 'file' checks, whether the file is removed before the task is started, so that one left from a previous run isn't mistaken for it.
+  #[serde(default, skip_serializing_if="is_false")]
+  remove: bool,
+}
+
+fn is_false(val: &bool) -> bool {
+  !*val
+}
+
+impl Check {
+  pub fn new(url: &str) -> Self {
+    Self{
+      url: url.to_owned(),
+      interval: interval_default(),
+      compact: true,
+      fresh: false,
+      non_empty: false,
+      contains: None,
+      socket: false,
+      remove: false,
+    }
+  }
+
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  pub fn with_url(&self, url: &str) -> Self {
+    let mut dup = self.clone();
+    dup.url = url.to_owned();
+    dup
+  }
+
+  /// Whether the check was described by its URL alone, instead of an object.
+  pub fn is_compact(&self) -> bool {
+    self.compact
+  }
+
+  // Whether any of the options that only apply to 'file' checks is set.
+  fn has_file_options(&self) -> bool {
+    self.fresh || self.non_empty || self.contains.is_some() || self.socket || self.remove
+  }
+
+  /// Check that the check's options apply to it.
+  pub fn validate(&self) -> Result<()> {
+    if self.has_file_options() && !self.url.starts_with("file:") {
+      return Err(error::AwaitError::new(&format!("Options 'fresh', 'non_empty', 'contains', 'socket' and 'remove' only apply to 'file' checks: {}", self.url)).into());
+    }
+    if let Some(port) = self.url.strip_prefix("listen:") {
+      if listen::parse_port(port).is_none() {
+        return Err(error::AwaitError::new(&format!("Invalid port: {}; a 'listen' check waits for a port, like 'listen:8080', or for any port, as 'listen:any': {}", port, self.url)).into());
+      }
+      if !cfg!(target_os = "linux") {
+        return Err(error::AwaitError::new(&format!("'listen' checks are only supported on Linux: {}", self.url)).into());
+      }
+    }
+    if let Some(pattern) = &self.contains {
+      if let Err(err) = regex::Regex::new(pattern) {
+        return Err(error::AwaitError::new(&format!("Invalid pattern: {}; because: {}: {}", pattern, err, self.url)).into());
+      }
+    }
+    Ok(())
+  }
+
+  /// Prepare for a task to be started, which removes the file a check waits
+  /// for when it asks for that.
+  pub fn prepare(&self) -> Result<()> {
+    if !self.remove {
+      return Ok(());
+    }
+    let url = url::Url::parse(&self.url)?;
+    match fs::remove_file(url.path()) {
+      Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+      _ => Ok(()),
+    }
+  }
+}
+
+impl fmt::Display for Check {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.url)
+  }
+}
+
+impl Serialize for Check {
+  fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+    match self.compact {
+      true  => serializer.serialize_str(&self.url),
+      false => CheckSpec{
+        url: self.url.clone(),
+        interval: self.interval,
+        fresh: self.fresh,
+        non_empty: self.non_empty,
+        contains: self.contains.clone(),
+        socket: self.socket,
+        remove: self.remove,
+      }.serialize(serializer),
     }
   }
 }
 
-pub async fn wait_config(conf: &Config, urls: &Vec<String>, timeout: time::Duration) -> Result<()> {
+impl<'de> Deserialize<'de> for Check {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+    struct CheckVisitor;
+
+    impl<'de> de::Visitor<'de> for CheckVisitor {
+      type Value = Check;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a check URL or object")
+      }
+
+      fn visit_str<E: de::Error>(self, v: &str) -> result::Result<Check, E> {
+        Ok(Check::new(v))
+      }
+
+      fn visit_map<M: de::MapAccess<'de>>(self, m: M) -> result::Result<Check, M::Error> {
+        let spec = CheckSpec::deserialize(de::value::MapAccessDeserializer::new(m))?;
+        Ok(Check{
+          url: spec.url,
+          interval: spec.interval,
+          compact: false,
+          fresh: spec.fresh,
+          non_empty: spec.non_empty,
+          contains: spec.contains,
+          socket: spec.socket,
+          remove: spec.remove,
+        })
+      }
+    }
+
+    deserializer.deserialize_any(CheckVisitor)
+  }
+}
+
+// A check is described either by its URL or an object, as it is deserialized.
+impl JsonSchema for Check {
+  fn schema_name() -> std::borrow::Cow<'static, str> {
+    "Check".into()
+  }
+
+  fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+      "anyOf": [schema::check_url(gen), gen.subschema_for::<CheckSpec>()],
+    })
+  }
+}
+
+pub async fn wait_config(conf: &Config, checks: &Vec<Check>, timeout: time::Duration) -> Result<()> {
   if conf.verbose {
-    for u in urls {
+    for c in checks {
       match &conf.key {
-        Some(key) => eprintln!("{}", &format!("----> {}: ... {}", key, u).italic()),
-        None      => eprintln!("{}", &format!("----> ... {}", u).italic()),
+        Some(key) => eprintln!("{}", &format!("----> {}: ... {}", key, c).italic()),
+        None      => eprintln!("{}", &format!("----> ... {}", c).italic()),
       }
     }
   }
-  try_join_all(wait_jobs(urls, timeout)?).await?;
+  try_join_all(wait_jobs(conf, checks, timeout)?).await?;
   Ok(())
 }
 
-pub fn wait_jobs<'a>(urls: &'a Vec<String>, timeout: time::Duration) -> Result<Vec<Pin<Box<dyn futures::Future<Output = Result<()>> + 'a>>>> {
+#[allow(clippy::type_complexity)]
+pub fn wait_jobs<'a>(conf: &Config, checks: &'a Vec<Check>, timeout: time::Duration) -> Result<Vec<Pin<Box<dyn futures::Future<Output = Result<()>> + 'a>>>> {
   let deadline = SystemTime::now() + timeout;
   let mut jobs: Vec<Pin<Box<dyn futures::Future<Output = Result<()>>>>> = Vec::new();
-  for base in urls {
+  for check in checks {
+    let base = check.url();
     let url = url::Url::parse(base)?;
     let scheme = url.scheme();
     match scheme {
-      "http" | "https" => jobs.push(Box::pin(wait_http(base, deadline))),
-      "file"           => jobs.push(Box::pin(wait_file(base, deadline))),
-      "shell"          => jobs.push(Box::pin(wait_shell(base, deadline))),
+      "http" | "https" => jobs.push(Box::pin(wait_http(base, deadline, check.interval))),
+      "file"           => jobs.push(Box::pin(wait_file(check, conf.started, deadline))),
+      "listen"         => match conf.pid {
+        Some(pid) => jobs.push(Box::pin(wait_listen(base, pid, deadline, check.interval))),
+        None => return Err(error::AwaitError::new(&format!("A 'listen' check requires a task: {}", base)).into()),
+      },
+      "shell"          => jobs.push(Box::pin(wait_shell(base, deadline, check.interval))),
       _                => return Err(error::AwaitError::new(&format!("Scheme '{}' not supported: {}", scheme, base)).into())
     }
   }
   Ok(jobs)
 }
 
-async fn wait_fn<F>(url: &str, deadline: SystemTime, func: F) -> Result<()>
+async fn wait_fn<F>(url: &str, deadline: SystemTime, wait: time::Duration, func: F) -> Result<()>
 where
   F: Fn(String, time::Duration) -> Pin<Box<dyn Future<Output = Result<bool>>>>
 {
-  let wait = time::Duration::from_secs(1);
   loop {
     let before = SystemTime::now();
     if (func(url.to_string(), deadline.duration_since(SystemTime::now())?).await).unwrap_or_default() {
@@ -81,8 +299,8 @@ where
   }
 }
 
-async fn wait_http(url: &str, deadline: SystemTime) -> Result<()> {
-  wait_fn(url, deadline, |u, t| {
+async fn wait_http(url: &str, deadline: SystemTime, interval: time::Duration) -> Result<()> {
+  wait_fn(url, deadline, interval, |u, t| {
     Box::pin(async move {
       match reqwest::Client::new().get(u).timeout(t).send().await {
         Ok(rsp)  => Ok(rsp.status().is_success()),
@@ -92,19 +310,60 @@ async fn wait_http(url: &str, deadline: SystemTime) -> Result<()> {
   }).await
 }
 
-async fn wait_file(url: &str, deadline: SystemTime) -> Result<()> {
-  wait_fn(url, deadline, |u, _| {
+async fn wait_file(check: &Check, started: SystemTime, deadline: SystemTime) -> Result<()> {
+  let contains = match &check.contains {
+    Some(pattern) => Some(regex::Regex::new(pattern).map_err(|err| error::AwaitError::new(&format!("Invalid pattern: {}; because: {}", pattern, err)))?),
+    None => None,
+  };
+  // File times are taken from a coarse clock, which can be a little behind.
+  let started = started - time::Duration::from_millis(10);
+  let (url, interval) = (check.url.clone(), check.interval);
+  let check = check.clone();
+  wait_fn(&url, deadline, interval, move |u, _| {
+    let check = check.clone();
+    let contains = contains.clone();
     Box::pin(async move {
-      match url::Url::parse(&u) {
-        Ok(u)    => Ok(path::Path::new(u.path()).exists()),
-        Err(err) => Err(err.into()),
+      let u = url::Url::parse(&u)?;
+      let path = path::Path::new(u.path());
+      let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(false),
+      };
+      if check.fresh && meta.modified()? < started {
+        return Ok(false);
+      }
+      if check.non_empty && meta.len() == 0 {
+        return Ok(false);
+      }
+      if check.socket {
+        use std::os::unix::fs::FileTypeExt;
+        if !meta.file_type().is_socket() || tokio::net::UnixStream::connect(path).await.is_err() {
+          return Ok(false);
+        }
       }
+      if let Some(contains) = &contains {
+        if !contains.is_match(&String::from_utf8_lossy(&fs::read(path)?)) {
+          return Ok(false);
+        }
+      }
+      Ok(true)
     })
   }).await
 }
 
-async fn wait_shell(url: &str, deadline: SystemTime) -> Result<()> {
-  wait_fn(url, deadline, |u, _| {
+async fn wait_listen(url: &str, pid: u32, deadline: SystemTime, interval: time::Duration) -> Result<()> {
+  wait_fn(url, deadline, interval, move |u, _| {
+    Box::pin(async move {
+      match u.strip_prefix("listen:").and_then(listen::parse_port) {
+        Some(port) => Ok(tokio::task::spawn_blocking(move || listen::listening(pid as i32, port)).await.map_err(io::Error::from)?),
+        None => Err(error::AwaitError::new(&format!("Invalid port: {}", u)).into()),
+      }
+    })
+  }).await
+}
+
+async fn wait_shell(url: &str, deadline: SystemTime, interval: time::Duration) -> Result<()> {
+  wait_fn(url, deadline, interval, |u, _| {
     Box::pin(async move {
       let cmd = match url::Url::parse(&u) {
         Ok(u) => {
@@ -127,3 +386,66 @@ async fn wait_shell(url: &str, deadline: SystemTime) -> Result<()> {
     })
   }).await
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A check of a file, with options.
+  fn check(path: &path::Path, opts: &str) -> Check {
+    serde_yaml::from_str(&format!("{{url: 'file://{}', interval: 10ms, {}}}", path.display(), opts)).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_file() {
+    let dir = std::env::temp_dir().join(format!("psctl-test-waiter-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("app.pid");
+    let deadline = || SystemTime::now() + time::Duration::from_millis(200);
+    let before = SystemTime::now() - time::Duration::from_secs(60);
+
+    fs::write(&file, "").unwrap();
+    let after = SystemTime::now() + time::Duration::from_secs(60);
+    assert!(wait_file(&check(&file, "fresh: true"), after, deadline()).await.is_err()); // left from a previous run
+    assert!(wait_file(&check(&file, "fresh: true"), before, deadline()).await.is_ok());
+    assert!(wait_file(&check(&file, "non_empty: true"), before, deadline()).await.is_err());
+
+    // a file that doesn't match yet is waited on until it does
+    fs::write(&file, "starting").unwrap();
+    assert!(wait_file(&check(&file, "contains: '^ready'"), before, deadline()).await.is_err());
+    let write = {
+      let file = file.clone();
+      tokio::spawn(async move {
+        sleep(time::Duration::from_millis(50)).await;
+        fs::write(&file, "ready").unwrap();
+      })
+    };
+    assert!(wait_file(&check(&file, "contains: '^ready'"), before, SystemTime::now() + time::Duration::from_secs(5)).await.is_ok());
+    write.await.unwrap();
+
+    assert!(wait_file(&check(&file, "socket: true"), before, deadline()).await.is_err());
+    let sock = dir.join("app.sock");
+    let _listener = tokio::net::UnixListener::bind(&sock).unwrap();
+    assert!(wait_file(&check(&sock, "socket: true"), before, deadline()).await.is_ok());
+
+    check(&file, "fresh: true").prepare().unwrap();
+    assert!(file.exists());
+    check(&file, "remove: true").prepare().unwrap();
+    assert!(!file.exists());
+    check(&file, "remove: true").prepare().unwrap(); // already gone
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_validate() {
+    let check: Check = serde_yaml::from_str("{url: 'file:///tmp/app.pid', fresh: true, contains: '^\\d+$'}").unwrap();
+    assert!(check.validate().is_ok());
+    let check: Check = serde_yaml::from_str("{url: 'file:///tmp/app.pid', contains: '('}").unwrap();
+    assert!(check.validate().is_err());
+    for opts in ["fresh: true", "non_empty: true", "contains: ready", "socket: true", "remove: true"] {
+      let check: Check = serde_yaml::from_str(&format!("{{url: 'http://localhost:8080/', {}}}", opts)).unwrap();
+      assert!(check.validate().is_err());
+    }
+  }
+
+}